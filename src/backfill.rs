@@ -0,0 +1,315 @@
+//! Record the photos of a chat history exported by Telegram Desktop, so reposts
+//! of images posted before the bot joined are found.
+//!
+//! The export is a directory with a `result.json` and the media files it
+//! refers to by relative paths, e.g. `photos/photo_1@01-01-2024_12-00-00.jpg`.
+//!
+//! Telegram Desktop exports the largest size of a photo, while the bot hashes
+//! the size selected by `photo_size`, so the sha of a photo only matches if
+//! `photo_size = "largest"`. Otherwise photos are recorded under a key which
+//! never matches a downloaded file, and are found by perceptual hash only.
+//! Image documents are exported as sent, so they match by sha as well.
+
+use std::{
+    fs,
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::{
+    config::{chat_config, PhotoSizeSelection},
+    db::{retention, ChatKey, DbOperation, MarsImage, Occurrence},
+    hash::{sha3_256, ImageHash},
+};
+
+/// The `result.json` of an exported chat. Unused fields are omitted.
+#[derive(Debug, Deserialize)]
+struct ChatExport {
+    id: Option<i64>,
+    messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedMessage {
+    id: i32,
+    /// `message`, or `service` for joins, pins and so on
+    #[serde(rename = "type")]
+    ty: String,
+    /// the unix timestamp as a string, missing in older exports
+    date_unixtime: Option<String>,
+    /// `user<id>`, `channel<id>` or `chat<id>`
+    from_id: Option<String>,
+    /// the path of a photo, or a note if the file is not exported
+    photo: Option<String>,
+    /// the path of a document
+    file: Option<String>,
+    mime_type: Option<String>,
+    /// set for stickers, videos and so on, but not plain documents
+    media_type: Option<String>,
+}
+
+impl ExportedMessage {
+    /// The relative path of the photo or image document, and whether it is a
+    /// photo.
+    fn image(&self) -> Option<(&str, bool)> {
+        if self.ty != "message" {
+            return None;
+        }
+        if let Some(photo) = &self.photo {
+            return Some((photo, true));
+        }
+        self.file
+            .as_deref()
+            .filter(|_| {
+                self.media_type.is_none()
+                    && self
+                        .mime_type
+                        .as_deref()
+                        .is_some_and(|x| x.starts_with("image/"))
+            })
+            .map(|x| (x, false))
+    }
+
+    fn time(&self) -> Option<i64> {
+        self.date_unixtime.as_deref()?.parse().ok()
+    }
+
+    /// The sender as the bot sees it: a user id, or a chat id for channels and
+    /// groups.
+    fn sender(&self) -> Option<i64> {
+        let from_id = self.from_id.as_deref()?;
+        if let Some(id) = from_id.strip_prefix("user") {
+            id.parse().ok()
+        } else if let Some(id) = from_id.strip_prefix("channel") {
+            id.parse::<i64>().ok().map(|x| -1_000_000_000_000 - x)
+        } else {
+            from_id
+                .strip_prefix("chat")?
+                .parse::<i64>()
+                .ok()
+                .map(|x| -x)
+        }
+    }
+}
+
+/// The result of [`backfill`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Backfilled {
+    /// images recorded for the first time
+    pub recorded: usize,
+    /// images which are already recorded by an earlier message
+    pub repeated: usize,
+    /// images which are not exported, outside the export, too large or
+    /// expired
+    pub skipped: usize,
+}
+
+/// Hash every photo and image document of the export in `dir`, and record them
+/// to `table` with their original message ids. Existing records are kept.
+pub fn backfill<D: DbOperation + ?Sized>(
+    db: &D,
+    table: &ChatKey,
+    dir: &Path,
+) -> Result<Backfilled> {
+    let path = dir.join("result.json");
+    let export: ChatExport = serde_json::from_slice(
+        &fs::read(&path).with_context(|| format!("read `{}` failed", path.display()))?,
+    )
+    .with_context(|| format!("parse `{}` failed", path.display()))?;
+    if let Some(id) = export.id {
+        if table.id() != id && table.as_str() != format!("-100{id}") {
+            warn!("the export is of chat {id}, but is recorded to chat {table}");
+        }
+    }
+    let config = chat_config(db, table)?;
+    let cutoff = config.retention.cutoff(retention::now());
+    let mut result = Backfilled::default();
+    let mut batch = vec![];
+    for message in &export.messages {
+        let Some((image, is_photo)) = message.image() else {
+            continue;
+        };
+        if !is_inside(image) {
+            warn!(
+                "skip image `{image}` of message {}, which is outside the export",
+                message.id
+            );
+            result.skipped += 1;
+            continue;
+        }
+        let file = dir.join(image);
+        let size = fs::metadata(&file).map(|x| x.len());
+        if !size.is_ok_and(|x| x <= u64::from(config.max_file_size))
+            || retention::is_expired(message.time(), cutoff)
+        {
+            debug!("skip image `{image}` of message {}", message.id);
+            result.skipped += 1;
+            continue;
+        }
+        let bytes = fs::read(&file).with_context(|| format!("read `{}` failed", file.display()))?;
+        let hash = ImageHash::new(&bytes, config.perceptual_hash);
+        let sha = if is_photo && config.photo_size != PhotoSizeSelection::Largest {
+            photo_key(&hash.sha)
+        } else {
+            hash.sha
+        };
+        let item = MarsImage::new(message.id, sha)
+            .with_phash(hash.phash)
+            .with_time(message.time());
+        batch.push((
+            item,
+            Occurrence::new(message.id, message.time(), message.sender()),
+        ));
+        if batch.len() == BATCH_SIZE {
+            record(db, table, &mut batch, &mut result)?;
+        }
+    }
+    record(db, table, &mut batch, &mut result)?;
+    Ok(result)
+}
+
+/// Whether the relative `path` in `result.json` stays inside the export.
+fn is_inside(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|x| matches!(x, Component::Normal(_) | Component::CurDir))
+}
+
+/// The key of an exported photo, which is another size than the downloaded
+/// one. It never equals the sha of a file, but is the same for the same photo,
+/// so repeated photos of the export are still found.
+fn photo_key(sha: &[u8]) -> Vec<u8> {
+    sha3_256(&[b"backfilled photo:", sha].concat())
+}
+
+/// The number of images inserted at once.
+const BATCH_SIZE: usize = 256;
+
+/// Insert a batch of images with their occurrences, and count them to
+/// `result`.
+fn record<D: DbOperation + ?Sized>(
+    db: &D,
+    table: &ChatKey,
+    batch: &mut Vec<(MarsImage, Occurrence)>,
+    result: &mut Backfilled,
+) -> Result<()> {
+    let (items, occurrences): (Vec<_>, Vec<_>) = batch.drain(..).unzip();
+    let shas = items.iter().map(|x| x.sha.clone()).collect::<Vec<_>>();
+    let inserted = db.insert_many(table, items)?;
+    for ((existing, sha), occurrence) in inserted.into_iter().zip(shas).zip(occurrences) {
+        let sha = if let Some(existing) = existing {
+            result.repeated += 1;
+            existing.sha
+        } else {
+            result.recorded += 1;
+            sha
+        };
+        db.add_occurrence(table, &sha, occurrence)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::db::tests::test_dbs;
+
+    fn write_image(path: &Path, color: u8) {
+        RgbImage::from_fn(16, 16, |x, _| {
+            Rgb([color, u8::try_from(x).unwrap() * 16, 0])
+        })
+        .save_with_format(path, ImageFormat::Png)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_is_inside() {
+        assert!(is_inside("photos/1.jpg"));
+        assert!(is_inside("./photos/1.jpg"));
+        assert!(!is_inside("../1.jpg"));
+        assert!(!is_inside("photos/../../1.jpg"));
+        assert!(!is_inside("/etc/passwd"));
+    }
+
+    #[test]
+    fn test_backfill() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("photos")).unwrap();
+        write_image(&dir.path().join("photos/1.png"), 0);
+        write_image(&dir.path().join("photos/2.png"), 255);
+        fs::copy(
+            dir.path().join("photos/1.png"),
+            dir.path().join("photos/3.png"),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("result.json"),
+            r#"{
+                "name": "group", "type": "private_supergroup", "id": 123,
+                "messages": [
+                    {"id": 1, "type": "message", "date_unixtime": "100", "from_id": "user7", "photo": "photos/1.png"},
+                    {"id": 2, "type": "message", "from_id": "channel8", "file": "photos/2.png", "mime_type": "image/png"},
+                    {"id": 3, "type": "message", "date_unixtime": "300", "from_id": "user9", "photo": "photos/3.png"},
+                    {"id": 4, "type": "message", "photo": "(File not included. Change data exporting settings to download.)"},
+                    {"id": 5, "type": "message", "file": "photos/2.png", "mime_type": "image/png", "media_type": "sticker"},
+                    {"id": 6, "type": "service", "action": "pin_message"},
+                    {"id": 7, "type": "message", "text": "hello"},
+                    {"id": 8, "type": "message", "photo": "../outside.png"},
+                    {"id": 9, "type": "message", "file": "/etc/outside.png", "mime_type": "image/png"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        for (_dir, db) in test_dbs() {
+            let result = backfill(&*db, &ChatKey::from(-100_123), dir.path()).unwrap();
+            assert_eq!(
+                result,
+                Backfilled {
+                    recorded: 2,
+                    repeated: 1,
+                    skipped: 3,
+                }
+            );
+            let mut all = db.query_all_from_table(&ChatKey::from(-100_123)).unwrap();
+            all.sort_by_key(|x| x.id);
+            assert_eq!(all.iter().map(|x| x.id).collect::<Vec<_>>(), vec![1, 2]);
+            assert_eq!(all[0].time, Some(100));
+            assert!(all[0].phash.is_some());
+            // a photo never matches a downloaded size by sha, but a document
+            // does
+            let sha = |name| sha3_256(&fs::read(dir.path().join("photos").join(name)).unwrap());
+            assert_eq!(all[0].sha, photo_key(&sha("1.png")));
+            assert_eq!(all[1].sha, sha("2.png"));
+            assert_eq!(
+                db.add_occurrence(
+                    &ChatKey::from(-100_123),
+                    &all[0].sha,
+                    Occurrence::new(3, None, None)
+                )
+                .unwrap(),
+                vec![
+                    Occurrence::new(1, Some(100), Some(7)),
+                    Occurrence::new(3, Some(300), Some(9)),
+                ]
+            );
+            assert_eq!(
+                db.add_occurrence(
+                    &ChatKey::from(-100_123),
+                    &all[1].sha,
+                    Occurrence::new(2, None, None)
+                )
+                .unwrap(),
+                vec![Occurrence::new(2, None, Some(-1_000_000_000_008))]
+            );
+            // backfilling again records nothing new
+            let result = backfill(&*db, &ChatKey::from(-100_123), dir.path()).unwrap();
+            assert_eq!(result.recorded, 0);
+        }
+    }
+}
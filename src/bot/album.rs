@@ -0,0 +1,131 @@
+//! Items of an album (media group) arrive as separate messages sharing a
+//! `media_group_id`. Their results are buffered until no item arrives for
+//! `album_wait_millis`, then one reply is sent for the whole album.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use dyn_fmt::AsStrFormatExt;
+use log::{debug, info};
+use teloxide::{prelude::*, types::MessageId};
+
+use super::respond::respond;
+use crate::{config::Config, utils::msg_url};
+
+static ALBUMS: LazyLock<Mutex<HashMap<(ChatId, String), Album>>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Default)]
+struct Album {
+    /// increased on every item, so only the last scheduled flush sends the
+    /// reply
+    generation: u64,
+    /// the first item, which the reply is sent to
+    first: Option<Message>,
+    /// `(message id, origin message id)` of each item
+    items: Vec<(i32, Option<i32>)>,
+}
+
+impl Album {
+    /// Add an item, returns the new generation.
+    fn push(&mut self, message: &Message, origin: Option<i32>) -> u64 {
+        self.generation += 1;
+        self.items.push((message.id.0, origin));
+        if self.first.as_ref().is_none_or(|x| x.id.0 > message.id.0) {
+            self.first = Some(message.clone());
+        }
+        self.generation
+    }
+}
+
+/// Record an item of album `group`, and schedule a reply for the album by
+/// the `config` of the chat.
+pub fn record(
+    bot: &'static Bot,
+    message: &Message,
+    group: &str,
+    origin: Option<i32>,
+    config: Arc<Config>,
+) {
+    let key = (message.chat.id, group.to_owned());
+    let generation = ALBUMS
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_default()
+        .push(message, origin);
+    let wait = Duration::from_millis(config.album_wait_millis);
+    tokio::spawn(async move {
+        tokio::time::sleep(wait).await;
+        let album = {
+            let mut albums = ALBUMS.lock().unwrap();
+            if albums.get(&key).is_none_or(|x| x.generation != generation) {
+                return;
+            }
+            albums.remove(&key).expect("album must exist")
+        };
+        Box::pin(flush(bot, &key.1, album, &config)).await;
+    });
+}
+
+/// Answer the album prompt if the album is a Mars. All marsed items are
+/// answered as reposts.
+async fn flush(bot: &Bot, group: &str, mut album: Album, config: &Config) {
+    let Some(first) = album.first else {
+        return;
+    };
+    album.items.sort_unstable();
+    let Some(marsed) = marsed_items(&album.items, config.album_mars_all) else {
+        debug!("album {group} is not a Mars");
+        return;
+    };
+    info!("mars album {group}, marsed items: {marsed:?}");
+    let reposts = marsed
+        .iter()
+        .map(|&(index, _)| MessageId(album.items[index - 1].0))
+        .collect::<Vec<_>>();
+    let links = marsed
+        .into_iter()
+        .map(|(index, origin)| {
+            let url = msg_url(first.chat.invite_link(), first.chat.id.0, origin);
+            format!("[{index}]({url})")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let text = config.album_prompt.format(&[links]);
+    respond(bot, &first, &reposts, text, config).await;
+}
+
+/// The 1-based indexes of the marsed items in a sorted album, with their
+/// origin message ids. Returns `None` if no item is marsed, or not all items
+/// are marsed and `all` is set.
+fn marsed_items(items: &[(i32, Option<i32>)], all: bool) -> Option<Vec<(usize, i32)>> {
+    let marsed = items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (_, origin))| origin.map(|x| (index + 1, x)))
+        .collect::<Vec<_>>();
+    if marsed.is_empty() || (all && marsed.len() < items.len()) {
+        return None;
+    }
+    Some(marsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marsed_items() {
+        let items = [(10, None), (11, Some(3)), (12, Some(4))];
+        assert_eq!(marsed_items(&items, false), Some(vec![(2, 3), (3, 4)]));
+        assert_eq!(marsed_items(&items, true), None);
+        assert_eq!(marsed_items(&[(10, None)], false), None);
+        assert_eq!(
+            marsed_items(&[(10, Some(1)), (11, Some(2))], true),
+            Some(vec![(1, 1), (2, 2)])
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Commands to manage the records of a chat from the chat itself. Commands
+//! changing records are only for chat administrators.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{debug, error, info};
+use teloxide::{
+    prelude::*,
+    types::{Chat, MessageReactionUpdated, ReactionType, ReplyParameters, User},
+    utils::command::{BotCommands, ParseError},
+};
+
+use super::{config_of, media::Media, sticker, text};
+use crate::{
+    config::{ChatSettings, Config, CONFIG},
+    db::{convert::TableCounts, ChatKey, DbOperation, MarsImage, ASYNC_DB},
+    hash::sha3_256,
+    utils::OnceLockDefaultInit,
+};
+
+// the variants are the command names
+#[allow(clippy::enum_variant_names)]
+#[derive(BotCommands, Clone, Debug, PartialEq, Eq)]
+#[command(rename_rule = "snake_case", description = "Mars bot commands:")]
+pub enum Command {
+    #[command(description = "show this help.")]
+    MarsHelp,
+    #[command(description = "show the number of records of this chat.")]
+    MarsStats,
+    #[command(description = "reply to a message to forget its images and texts, for admins.")]
+    MarsForget,
+    #[command(description = "forget all records of this chat, for admins.")]
+    MarsReset,
+    #[command(description = "show the settings of this chat.")]
+    MarsSettings,
+    #[command(
+        description = "change a setting of this chat by key=value, for admins.",
+        parse_with = whole_text
+    )]
+    MarsSet(String),
+}
+
+impl Command {
+    /// Whether the command changes records of the chat.
+    const fn is_destructive(&self) -> bool {
+        matches!(self, Self::MarsForget | Self::MarsReset | Self::MarsSet(_))
+    }
+}
+
+/// Take the whole text after the command as the argument.
+#[allow(clippy::unnecessary_wraps)] // the signature of `parse_with`
+const fn whole_text(text: String) -> Result<(String,), ParseError> {
+    Ok((text,))
+}
+
+/// Run `command` of `message`, and reply the result.
+pub async fn answer(bot: Bot, message: Message, command: Command) -> ResponseResult<()> {
+    info!("command {command:?} in chat {}", message.chat.id);
+    let chat = ChatKey::from(message.chat.id);
+    let text = if command.is_destructive()
+        && !is_admin(
+            &bot,
+            &message.chat,
+            message.from.as_ref(),
+            message.sender_chat.as_ref(),
+        )
+        .await?
+    {
+        "Only administrators of this chat can do this.".to_owned()
+    } else {
+        match command {
+            Command::MarsHelp => Command::descriptions().to_string(),
+            Command::MarsStats => stats(chat).await,
+            Command::MarsForget => match message.reply_to_message() {
+                Some(target) => {
+                    let config = config_of(chat.clone()).await;
+                    let fingerprints = fingerprints(&bot, target, &config).await;
+                    forget(chat, target.id.0, fingerprints, config).await
+                }
+                None => "Reply /mars_forget to the message to forget.".to_owned(),
+            },
+            Command::MarsReset => reset(chat).await,
+            Command::MarsSettings => settings(chat).await,
+            Command::MarsSet(pair) => set(chat, pair).await,
+        }
+    };
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await?;
+    Ok(())
+}
+
+/// Forget the message reacted with `forget_reaction` by an administrator.
+/// Only records first posted by the message are forgotten, as its content is
+/// not known.
+pub async fn react(bot: Bot, reaction: MessageReactionUpdated) -> ResponseResult<()> {
+    let chat = ChatKey::from(reaction.chat.id);
+    let config = config_of(chat.clone()).await;
+    let is_forget = |x: &[_]| {
+        x.iter()
+            .any(|x: &ReactionType| x.emoji() == Some(&config.forget_reaction))
+    };
+    if config.forget_reaction.is_empty()
+        || !is_forget(&reaction.new_reaction)
+        || is_forget(&reaction.old_reaction)
+        || !is_admin(&bot, &reaction.chat, reaction.user(), reaction.actor_chat()).await?
+    {
+        return Ok(());
+    }
+    let id = reaction.message_id.0;
+    info!("forget message {id} of chat {chat} by reaction");
+    let result = forget(chat, id, Fingerprints::default(), config).await;
+    debug!("{result}");
+    Ok(())
+}
+
+/// Whether `user`, or `sender_chat` for an anonymous administrator,
+/// administrates `chat`. An anonymous administrator acts as the chat itself,
+/// and everyone administrates their private chat.
+async fn is_admin(
+    bot: &Bot,
+    chat: &Chat,
+    user: Option<&User>,
+    sender_chat: Option<&Chat>,
+) -> ResponseResult<bool> {
+    if chat.is_private() || sender_chat.is_some_and(|x| x.id == chat.id) {
+        return Ok(true);
+    }
+    let Some(user) = user else {
+        return Ok(false);
+    };
+    Ok(bot.get_chat_member(chat.id, user.id).await?.is_privileged())
+}
+
+/// Log the error of a command, and describe it to the chat.
+fn failed(action: &str, e: &anyhow::Error) -> String {
+    error!("{action} failed: {e:?}");
+    format!("Failed to {action}, please check the log of the bot.")
+}
+
+async fn stats(chat: ChatKey) -> String {
+    match ASYNC_DB.run(move |db| TableCounts::of(db, &chat)).await {
+        Ok(counts) => format!(
+            "Recorded {} images, {} file ids, {} texts and {} occurrences.",
+            counts.records, counts.unique_ids, counts.texts, counts.occurrences
+        ),
+        Err(e) => failed("read the records", &e),
+    }
+}
+
+/// What the bot records of a message.
+#[derive(Debug, Default)]
+struct Fingerprints {
+    /// the hashes of its sticker and texts
+    shas: Vec<Vec<u8>>,
+    /// its hashed media
+    media: Option<MarsImage>,
+}
+
+/// Hash `message` again as the bot records it by the `config` of the chat.
+/// The media is only downloaded if its file is not recorded.
+async fn fingerprints(bot: &Bot, message: &Message, config: &Config) -> Fingerprints {
+    let mut result = Fingerprints::default();
+    if let Some(key) = sticker::sticker_key(message, config.sticker_match_set) {
+        result.shas.push(sha3_256(key.as_bytes()));
+    }
+    for key in text::text_keys(message, config) {
+        result.shas.push(sha3_256(key.as_bytes()));
+    }
+    let Some(media) = Media::from_message(message, config) else {
+        return result;
+    };
+    let (chat, unique_id) = (ChatKey::from(message.chat.id), media.file.unique_id.clone());
+    result.media = match ASYNC_DB
+        .run(move |db| db.query_by_unique_id(&chat, &unique_id))
+        .await
+    {
+        Ok(Some(x)) => Some(x),
+        Ok(None) => media
+            .download_and_hash(bot, message.id.0, config)
+            .await
+            .unwrap_or_else(|e| {
+                error!("hashing file `{}` to forget failed: {e:?}", media.file.id);
+                None
+            }),
+        Err(e) => {
+            error!("query file_unique_id from database failed: {e:?}");
+            None
+        }
+    };
+    result
+}
+
+async fn forget(
+    chat: ChatKey,
+    message_id: i32,
+    fingerprints: Fingerprints,
+    config: Arc<Config>,
+) -> String {
+    match ASYNC_DB
+        .run(move |db| forget_message(db, &chat, message_id, &fingerprints, &config))
+        .await
+    {
+        Ok(0) => "Nothing of this message is recorded.".to_owned(),
+        Ok(removed) => format!("Forgot {removed} records of this message."),
+        Err(e) => failed("forget the message", &e),
+    }
+}
+
+/// Remove the images and texts first posted by message `id`, and the records
+/// matching its `fingerprints`: the same hashes, and the image or video the
+/// media is similar to by `config`. Their file ids and occurrences are
+/// removed too.
+///
+/// # Returns
+///
+/// The number of removed images and texts.
+fn forget_message<D: DbOperation + ?Sized>(
+    db: &D,
+    chat: &ChatKey,
+    id: i32,
+    fingerprints: &Fingerprints,
+    config: &Config,
+) -> Result<usize> {
+    let mut shas = db
+        .query_all_from_table(chat)?
+        .into_iter()
+        .filter(|x| x.id == id)
+        .map(|x| x.sha)
+        .collect::<Vec<_>>();
+    shas.extend(fingerprints.shas.iter().cloned());
+    if let Some(media) = &fingerprints.media {
+        let threshold = config.similarity_threshold;
+        let similar = if media.kind.is_video() {
+            db.index()
+                .find_similar_video(db, chat, media, threshold, config.video_match_ratio, None)?
+                .map(|x| x.1)
+        } else if let Some(phash) = media.phash {
+            db.index()
+                .find_nearest(db, chat, phash, threshold, id, None)?
+                .map(|x| x.1)
+        } else {
+            None
+        };
+        shas.push(media.sha.clone());
+        shas.extend(similar.map(|x| x.sha));
+    }
+    shas.extend(
+        db.query_all_texts(chat)?
+            .into_iter()
+            .filter(|(_, x)| x.id == id)
+            .map(|(sha, _)| sha),
+    );
+    shas.sort_unstable();
+    shas.dedup();
+    let mut removed = 0;
+    for sha in shas {
+        removed += db.remove_by_key(chat, &sha)?;
+    }
+    if removed > 0 {
+        db.index().forget(chat);
+    }
+    Ok(removed)
+}
+
+async fn reset(chat: ChatKey) -> String {
+    match ASYNC_DB
+        .run(move |db| {
+            db.drop_table(&chat)?;
+            db.index().forget(&chat);
+            Ok(())
+        })
+        .await
+    {
+        Ok(()) => "Forgot all records of this chat.".to_owned(),
+        Err(e) => failed("reset the chat", &e),
+    }
+}
+
+/// Describe the settings of a chat with the effective config.
+fn describe(settings: &ChatSettings) -> String {
+    settings.describe(&CONFIG.get_or_init_default().for_chat(settings))
+}
+
+async fn settings(chat: ChatKey) -> String {
+    match ASYNC_DB
+        .run(move |db| db.query_settings(&chat).map(|x| describe(&x)))
+        .await
+    {
+        Ok(text) => text,
+        Err(e) => failed("read the settings", &e),
+    }
+}
+
+async fn set(chat: ChatKey, pair: String) -> String {
+    if pair.trim().is_empty() {
+        return format!(
+            "Usage: /mars_set key=value, an empty value unsets the key. Keys: {}",
+            ChatSettings::keys().join(", ")
+        );
+    }
+    let mut settings = match ASYNC_DB
+        .run({
+            let chat = chat.clone();
+            move |db| db.query_settings(&chat)
+        })
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => return failed("read the settings", &e),
+    };
+    if let Err(e) = settings.set_pair(&pair) {
+        return format!("{e}.");
+    }
+    match ASYNC_DB
+        .run(move |db| {
+            db.save_settings(&chat, &settings)?;
+            Ok(describe(&settings))
+        })
+        .await
+    {
+        Ok(text) => text,
+        Err(e) => failed("save the settings", &e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{tests::test_dbs, MarsImage, TextRecord};
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::parse("/mars_forget@mars_bot", "mars_bot").unwrap(),
+            Command::MarsForget
+        );
+        assert!(Command::parse("/mars_forget@other_bot", "mars_bot").is_err());
+        assert_eq!(
+            Command::parse("/mars_set mars_prompt=again and again", "mars_bot").unwrap(),
+            Command::MarsSet("mars_prompt=again and again".to_owned())
+        );
+        assert!(Command::MarsReset.is_destructive());
+        assert!(!Command::MarsStats.is_destructive());
+    }
+
+    #[test]
+    fn test_forget_message() {
+        for (_dir, db) in test_dbs() {
+            let chat = ChatKey::from(123);
+            // older versions recorded several sizes of a photo
+            db.insert_to_table(&chat, MarsImage::new(1, [1])).unwrap();
+            db.insert_to_table(&chat, MarsImage::new(1, [2])).unwrap();
+            db.insert_to_table(&chat, MarsImage::new(2, [3])).unwrap();
+            db.insert_or_get_existing_text(&chat, &[4], TextRecord::new(1, None))
+                .unwrap();
+            let forget = |id, fingerprints| {
+                forget_message(&*db, &chat, id, &fingerprints, &Config::default()).unwrap()
+            };
+            assert_eq!(forget(1, Fingerprints::default()), 3);
+            assert_eq!(forget(1, Fingerprints::default()), 0);
+            assert_eq!(
+                db.query_all_from_table(&chat).unwrap(),
+                vec![MarsImage::new(2, [3])]
+            );
+        }
+    }
+
+    #[test]
+    fn test_forget_fingerprints() {
+        for (_dir, db) in test_dbs() {
+            let chat = ChatKey::from(123);
+            db.insert_to_table(&chat, MarsImage::new(1, [1]).with_phash(Some(0b1111)))
+                .unwrap();
+            db.insert_to_table(&chat, MarsImage::new(2, [2]).with_phash(Some(u64::MAX)))
+                .unwrap();
+            db.insert_or_get_existing_text(&chat, &[3], TextRecord::new(3, None))
+                .unwrap();
+            // a repost of message 1, re-compressed, and of text 3
+            let fingerprints = Fingerprints {
+                shas: vec![vec![3]],
+                media: Some(MarsImage::new(4, [4]).with_phash(Some(0b0111))),
+            };
+            let forget = |fingerprints| {
+                forget_message(&*db, &chat, 4, &fingerprints, &Config::default()).unwrap()
+            };
+            assert_eq!(forget(fingerprints), 2);
+            assert_eq!(
+                db.query_all_from_table(&chat).unwrap(),
+                vec![MarsImage::new(2, [2]).with_phash(Some(u64::MAX))]
+            );
+            assert_eq!(db.query_all_texts(&chat).unwrap(), vec![]);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Rate limiting of Mars replies. Each chat and each sender in a chat has a
+//! token bucket; a reply is sent only if both have a token. Suppressed replies
+//! of a chat are counted for `summary_minutes`, then summarized in one message.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use teloxide::{
+    prelude::*,
+    requests::{Output, Request},
+    RequestError,
+};
+
+use super::ttl;
+use crate::config::{Config, RateLimit};
+
+static LIMITER: LazyLock<Mutex<Limiter>> = LazyLock::new(Mutex::default);
+
+/// The most times a request is retried when Telegram asks to retry later.
+const MAX_RETRIES: usize = 3;
+/// Idle buckets of senders are dropped beyond this number.
+const MAX_SENDER_BUCKETS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    /// Refill the tokens by the time passed since the last update.
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = elapsed
+            .mul_add(f64::from(limit.per_minute) / 60.0, self.tokens)
+            .min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: RateLimit) -> bool {
+        self.tokens >= f64::from(limit.burst)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    chats: HashMap<ChatId, TokenBucket>,
+    senders: HashMap<(ChatId, i64), TokenBucket>,
+    /// the number of suppressed replies of each chat since its summary is
+    /// scheduled
+    suppressed: HashMap<ChatId, usize>,
+}
+
+impl Limiter {
+    /// Take a token of `chat` and of `sender` if both have one. A zero
+    /// `burst` never limits.
+    fn allow(
+        &mut self,
+        chat: ChatId,
+        sender: Option<i64>,
+        limits: (RateLimit, RateLimit),
+        now: Instant,
+    ) -> bool {
+        let (chat_limit, sender_limit) = limits;
+        if self.senders.len() > MAX_SENDER_BUCKETS {
+            self.senders.retain(|_, x| {
+                x.refill(sender_limit, now);
+                !x.is_full(sender_limit)
+            });
+        }
+        let chat_bucket = (chat_limit.burst > 0).then(|| {
+            let bucket = self
+                .chats
+                .entry(chat)
+                .or_insert_with(|| TokenBucket::full(chat_limit, now));
+            bucket.refill(chat_limit, now);
+            bucket
+        });
+        let chat_has_token = chat_bucket.as_ref().is_none_or(|x| x.tokens >= 1.0);
+        let sender_bucket = sender.filter(|_| sender_limit.burst > 0).map(|x| {
+            let bucket = self
+                .senders
+                .entry((chat, x))
+                .or_insert_with(|| TokenBucket::full(sender_limit, now));
+            bucket.refill(sender_limit, now);
+            bucket
+        });
+        let sender_has_token = sender_bucket.as_ref().is_none_or(|x| x.tokens >= 1.0);
+        if !(chat_has_token && sender_has_token) {
+            return false;
+        }
+        for bucket in chat_bucket.into_iter().chain(sender_bucket) {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+
+    /// Count a suppressed reply of `chat`.
+    ///
+    /// # Returns
+    ///
+    /// Whether it is the first one since the last summary, so a summary
+    /// should be scheduled.
+    fn suppress(&mut self, chat: ChatId) -> bool {
+        let count = self.suppressed.entry(chat).or_default();
+        *count += 1;
+        *count == 1
+    }
+}
+
+/// Whether a Mars reply to `message` is allowed by the limits in `config`.
+pub fn allow(message: &Message, config: &Config) -> bool {
+    LIMITER.lock().unwrap().allow(
+        message.chat.id,
+        super::sender(message),
+        (config.chat_reply_limit, config.user_reply_limit),
+        Instant::now(),
+    )
+}
+
+/// Count a suppressed Mars reply of `chat`. The first one schedules a summary
+/// after `summary_minutes`.
+pub fn suppress(bot: &Bot, chat: ChatId, config: &Config) {
+    info!("Mars reply of chat {chat} is suppressed by rate limit");
+    if !LIMITER.lock().unwrap().suppress(chat) {
+        return;
+    }
+    let (bot, config) = (bot.clone(), Arc::new(config.clone()));
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(config.summary_minutes * 60)).await;
+        let count = LIMITER
+            .lock()
+            .unwrap()
+            .suppressed
+            .remove(&chat)
+            .unwrap_or_default();
+        let text = summary_text(&config, count);
+        match send_retrying(bot.send_message(chat, text)).await {
+            Ok(summary) => ttl::schedule(chat.into(), summary.id, &config).await,
+            Err(e) => warn!("send summary of suppressed replies failed: {e:?}"),
+        }
+    });
+}
+
+/// Fill `summary_prompt` with the `count` of suppressed replies.
+#[allow(clippy::literal_string_with_formatting_args)]
+fn summary_text(config: &Config, count: usize) -> String {
+    config
+        .summary_prompt
+        .replace("{count}", &count.to_string())
+        .replace("{minutes}", &config.summary_minutes.to_string())
+}
+
+/// Send `request`, and retry it after the wait Telegram asks for, at most
+/// `MAX_RETRIES` times.
+pub async fn send_retrying<R>(request: R) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+{
+    let mut retries = 0;
+    loop {
+        match request.send_ref().await {
+            Err(RequestError::RetryAfter(seconds)) if retries < MAX_RETRIES => {
+                warn!("flood limited by Telegram, retry after {seconds}");
+                retries += 1;
+                tokio::time::sleep(seconds.duration()).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT: ChatId = ChatId(-100_123);
+
+    #[test]
+    fn test_chat_limit() {
+        let mut limiter = Limiter::default();
+        let limit = RateLimit {
+            burst: 2,
+            per_minute: 6,
+        };
+        let limits = (limit, RateLimit::default());
+        let now = Instant::now();
+        assert!(limiter.allow(CHAT, Some(1), limits, now));
+        assert!(limiter.allow(CHAT, Some(2), limits, now));
+        assert!(!limiter.allow(CHAT, Some(3), limits, now));
+        assert!(limiter.allow(ChatId(1), Some(3), limits, now));
+        // a token every 10 seconds
+        assert!(!limiter.allow(CHAT, None, limits, now + Duration::from_secs(9)));
+        assert!(limiter.allow(CHAT, None, limits, now + Duration::from_secs(10)));
+        assert!(!limiter.allow(CHAT, None, limits, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_sender_limit() {
+        let mut limiter = Limiter::default();
+        let limit = RateLimit {
+            burst: 1,
+            per_minute: 1,
+        };
+        let limits = (
+            RateLimit {
+                burst: 2,
+                per_minute: 1,
+            },
+            limit,
+        );
+        let now = Instant::now();
+        assert!(limiter.allow(CHAT, Some(1), limits, now));
+        // a refused sender does not take the token of the chat
+        assert!(!limiter.allow(CHAT, Some(1), limits, now));
+        assert!(limiter.allow(CHAT, Some(2), limits, now));
+        assert!(!limiter.allow(CHAT, Some(3), limits, now));
+    }
+
+    #[test]
+    fn test_suppress() {
+        let mut limiter = Limiter::default();
+        assert!(limiter.suppress(CHAT));
+        assert!(!limiter.suppress(CHAT));
+        assert_eq!(limiter.suppressed[&CHAT], 2);
+    }
+}
@@ -0,0 +1,222 @@
+//! Find the media of a message, download and hash it.
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use log::{debug, trace, warn};
+use teloxide::{net::Download, prelude::*, types::FileMeta};
+
+use crate::{
+    config::Config,
+    db::{MarsImage, MediaKind},
+    hash::{perceptual_hash, sha3_256, video, ImageHash, PerceptualHash},
+};
+
+/// The media of a message to be hashed.
+#[derive(Debug)]
+pub struct Media<'a> {
+    pub kind: MediaKind,
+    pub file: &'a FileMeta,
+    /// the thumbnail generated by telegram, for video kinds
+    pub thumbnail: Option<&'a FileMeta>,
+    /// the duration in seconds, for video kinds
+    pub duration: Option<u32>,
+    /// the file is a GIF, which can be decoded without ffmpeg
+    pub gif: bool,
+    /// files larger than this are not downloaded
+    pub max_file_size: u32,
+}
+
+impl<'a> Media<'a> {
+    const fn new(kind: MediaKind, file: &'a FileMeta, max_file_size: u32) -> Self {
+        Self {
+            kind,
+            file,
+            thumbnail: None,
+            duration: None,
+            gif: false,
+            max_file_size,
+        }
+    }
+
+    /// Get the media to hash from a photo, an image document, or a video kind
+    /// if `detect_video` is set in the `config` of the chat. Returns `None` if
+    /// there is no such media or it is too large.
+    pub fn from_message(message: &'a Message, config: &Config) -> Option<Self> {
+        let max_file_size = config.max_file_size;
+        if let Some(sizes) = message.photo() {
+            debug!("{} is a photo message", message.id);
+            // only one size of the photo is hashed: the other sizes are scaled
+            // from the same image.
+            let photo = config.photo_size.select(
+                sizes,
+                config.photo_target_resolution,
+                config.max_file_size,
+            );
+            if let Some(photo) = photo {
+                debug!(
+                    "selected photo resolution: {}x{}",
+                    photo.width, photo.height
+                );
+            } else {
+                warn!(
+                    "all sizes of photo {} exceed size limit, do not record",
+                    message.id
+                );
+            }
+            return photo.map(|x| Self::new(MediaKind::Image, &x.file, max_file_size));
+        }
+        // images sent as file are not compressed by telegram
+        if let Some(document) = message.document().filter(|x| {
+            x.mime_type
+                .as_ref()
+                .is_some_and(|mime| mime.type_() == "image")
+        }) {
+            debug!("{} is an image document", message.id);
+            if document.file.size > config.max_file_size {
+                warn!(
+                    "document of {} exceed size limit, do not record",
+                    message.id
+                );
+                return None;
+            }
+            return Some(Self::new(MediaKind::Image, &document.file, max_file_size));
+        }
+        if !config.detect_video {
+            return None;
+        }
+        // large videos are still recorded by their thumbnail
+        let media = if let Some(video) = message.video() {
+            Self {
+                thumbnail: video.thumbnail.as_ref().map(|x| &x.file),
+                duration: Some(video.duration.seconds()),
+                ..Self::new(MediaKind::Video, &video.file, max_file_size)
+            }
+        } else if let Some(animation) = message.animation() {
+            Self {
+                thumbnail: animation.thumbnail.as_ref().map(|x| &x.file),
+                duration: Some(animation.duration.seconds()),
+                gif: animation
+                    .mime_type
+                    .as_ref()
+                    .is_some_and(|x| x.essence_str() == "image/gif"),
+                ..Self::new(MediaKind::Animation, &animation.file, max_file_size)
+            }
+        } else {
+            let note = message.video_note()?;
+            Self {
+                thumbnail: note.thumbnail.as_ref().map(|x| &x.file),
+                duration: Some(note.duration.seconds()),
+                ..Self::new(MediaKind::VideoNote, &note.file, max_file_size)
+            }
+        };
+        debug!("{} is a {:?} message", message.id, media.kind);
+        Some(media)
+    }
+
+    /// Download and hash the media by the `config` of the chat, returns the
+    /// record of message `id`, or `None` if the file is too large.
+    pub async fn download_and_hash(
+        &self,
+        bot: &Bot,
+        id: i32,
+        config: &Config,
+    ) -> Result<Option<MarsImage>> {
+        if self.kind.is_video() {
+            return self.download_video_and_hash(bot, id, config).await;
+        }
+        Ok(download_one_file_and_hash(
+            bot,
+            &self.file.id,
+            self.max_file_size,
+            config.perceptual_hash,
+        )
+        .await?
+        .map(|x| MarsImage::new(id, x.sha).with_phash(x.phash)))
+    }
+
+    /// Hash some frames of a video. Frames are extracted from GIFs, or by
+    /// ffmpeg if the `video` feature is enabled. If that is not possible, the
+    /// thumbnail is used as the only frame, and also as the sha if the video
+    /// is too large to download.
+    async fn download_video_and_hash(
+        &self,
+        bot: &Bot,
+        id: i32,
+        config: &Config,
+    ) -> Result<Option<MarsImage>> {
+        let (algorithm, count) = (config.perceptual_hash, config.video_frames);
+        let bytes = download_file(bot, &self.file.id, self.max_file_size).await?;
+        let mut sha = bytes.as_deref().map(sha3_256);
+        let mut frames = if let Some(bytes) = bytes {
+            let (gif, duration) = (self.gif, self.duration.unwrap_or_default());
+            let result = tokio::task::spawn_blocking(move || {
+                if gif {
+                    return video::gif_frames(&bytes, count, algorithm);
+                }
+                #[cfg(feature = "video")]
+                return video::ffmpeg_frames(&bytes, duration, count, algorithm);
+                #[cfg(not(feature = "video"))]
+                {
+                    _ = duration;
+                    Ok(vec![])
+                }
+            })
+            .await?;
+            result.unwrap_or_else(|e| {
+                warn!("extract frames of {:?} failed: {e:?}", self.kind);
+                vec![]
+            })
+        } else {
+            vec![]
+        };
+        if frames.is_empty() {
+            if let Some(thumbnail) = self.thumbnail {
+                trace!("hash the thumbnail of {:?} {id}", self.kind);
+                if let Some(bytes) = download_file(bot, &thumbnail.id, self.max_file_size).await? {
+                    sha.get_or_insert_with(|| sha3_256(&bytes));
+                    frames.extend(
+                        tokio::task::spawn_blocking(move || perceptual_hash(&bytes, algorithm))
+                            .await??,
+                    );
+                }
+            }
+        }
+        debug!("hash {} frames of {:?} {id}", frames.len(), self.kind);
+        Ok(sha.map(|sha| MarsImage::new(id, sha).with_video(self.kind, frames, self.duration)))
+    }
+}
+
+/// download a file, returns `Some(hash)` if hash successfully, or `Some(None)`
+/// if file size is larger than `max_size`.
+pub async fn download_one_file_and_hash(
+    bot: &Bot,
+    file_id: &str,
+    max_size: u32,
+    algorithm: PerceptualHash,
+) -> Result<Option<ImageHash>> {
+    let Some(bytes) = download_file(bot, file_id, max_size).await? else {
+        return Ok(None);
+    };
+    // decoding an image is cpu bound
+    Ok(Some(
+        tokio::task::spawn_blocking(move || ImageHash::new(&bytes, algorithm)).await?,
+    ))
+}
+
+/// download a file, returns `None` if file size is larger than `max_size`.
+async fn download_file(bot: &Bot, file_id: &str, max_size: u32) -> Result<Option<Bytes>> {
+    let file = bot.get_file(file_id).await?;
+    if file.size > max_size {
+        return Ok(None);
+    }
+    trace!("download_file_path: {}", file.path);
+
+    // download all trunks parallelly. code from https://github.com/capslock/stable-diffusion-bot/blob/main/crates/stable-diffusion-bot/src/bot/helpers.rs
+    Ok(Some(
+        bot.download_file_stream(&file.path)
+            .try_collect()
+            .await
+            .map(bytes::BytesMut::freeze)?,
+    ))
+}
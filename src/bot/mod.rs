@@ -0,0 +1,524 @@
+mod album;
+mod command;
+mod limit;
+mod media;
+mod respond;
+mod sticker;
+mod text;
+mod ttl;
+
+use core::str;
+use std::{sync::Arc, time::Duration};
+
+use command::Command;
+use dyn_fmt::AsStrFormatExt;
+use log::{debug, error, info, trace, warn};
+use media::Media;
+use teloxide::{prelude::*, utils::command::BotCommands};
+
+use crate::{
+    cli::Cli,
+    config::{self, Config, CONFIG},
+    db::{
+        retention::{self, is_expired},
+        ChatKey, DbOperation, MarsImage, Occurrence, TextRecord, ASYNC_DB, DB,
+    },
+    hash::sha3_256,
+    utils::{msg_url, OnceLockDefaultInit},
+};
+
+/// The config of `chat`, with the settings of the chat.
+async fn config_of(chat: ChatKey) -> Arc<Config> {
+    let config = ASYNC_DB
+        .run(move |db| config::chat_config(db, &chat))
+        .await
+        .unwrap_or_else(|e| {
+            error!("Error while query settings of chat: {e:?}");
+            CONFIG.get_or_init_default().clone()
+        });
+    Arc::new(config)
+}
+
+async fn handler(bot: &'static Bot, message: Message) {
+    let config = config_of(ChatKey::from(message.chat.id)).await;
+    // if `only_mars_for_channel_message` is set and the message is not sent by
+    // channel
+    if config.only_mars_for_channel_message && message.from.is_some() {
+        trace!("ignore message from user, because `only_mars_for_channel_message` is set");
+        return;
+    }
+    if message.chat.is_private() {
+        debug!("received message from private chat. It may not display a correct Mars link, because: https://t.me/withabsolutex/1841");
+    }
+    debug!(
+        "get message from chat {}: id {}",
+        message.chat.id, message.id
+    );
+    let found = find_origin(bot, &message, &config).await;
+    let chat_id = ChatKey::from(message.chat.id);
+    let occurrence = Occurrence::new(
+        message.id.0,
+        Some(message.date.timestamp()),
+        sender(&message),
+    );
+    let shas = found.shas;
+    let occurrences = ASYNC_DB
+        .run(move |db| {
+            let mut occurrences = vec![];
+            for sha in &shas {
+                match db.add_occurrence(&chat_id, sha, occurrence) {
+                    Ok(x) if occurrences.is_empty() => occurrences = x,
+                    Ok(_) => {}
+                    Err(e) => error!("Error while insert occurrence to database: {e:?}"),
+                }
+            }
+            Ok(occurrences)
+        })
+        .await
+        .unwrap_or_else(|e| {
+            error!("Error while insert occurrence to database: {e:?}");
+            vec![]
+        });
+    // items of an album arrive as separate messages, so they are replied
+    // together
+    if let Some(group) = message.media_group_id() {
+        album::record(
+            bot,
+            &message,
+            group,
+            found.origin.map(|x| x.0),
+            config.clone(),
+        );
+        return;
+    }
+    if let Some((origin, prompt)) = found.origin {
+        answer_mars(bot, &message, origin, &occurrences, prompt, &config).await;
+    }
+}
+
+/// The user or channel id of the sender of `message`.
+fn sender(message: &Message) -> Option<i64> {
+    message
+        .from
+        .as_ref()
+        .map(|x| x.id.0.cast_signed())
+        .or_else(|| message.sender_chat.as_ref().map(|x| x.id.0))
+}
+
+/// What [`find_origin`] found of a message.
+#[derive(Debug, Default)]
+struct Found<'a> {
+    /// the fingerprints the message is an occurrence of: the one it repeats if
+    /// it is a Mars, otherwise its own ones
+    shas: Vec<Vec<u8>>,
+    /// the origin message id, with the prompt to reply
+    origin: Option<(i32, &'a str)>,
+}
+
+impl Found<'_> {
+    /// Add what is found by another kind of fingerprint. The first origin
+    /// found wins, and its fingerprints go first, so the occurrences are the
+    /// ones of the origin.
+    fn merge(&mut self, other: Self) {
+        if self.origin.is_none() && other.origin.is_some() {
+            self.origin = other.origin;
+            let own = std::mem::replace(&mut self.shas, other.shas);
+            self.shas.extend(own);
+        } else {
+            self.shas.extend(other.shas);
+        }
+    }
+}
+
+/// Record every kind of fingerprint of `message`: the sticker, the text and
+/// the media. Then find the message it repeats by the `config` of the chat.
+async fn find_origin<'a>(bot: &Bot, message: &Message, config: &'a Arc<Config>) -> Found<'a> {
+    let mut found = find_sticker_origin(message, config).await;
+    found.merge(find_text_origin(message, config).await);
+    found.merge(Box::pin(find_media_origin(bot, message, config)).await);
+    found
+}
+
+/// Record the sticker of `message`, and find the message it repeats.
+async fn find_sticker_origin<'a>(message: &Message, config: &'a Arc<Config>) -> Found<'a> {
+    if !config.detect_sticker {
+        return Found::default();
+    }
+    let Some(key) = sticker::sticker_key(message, config.sticker_match_set) else {
+        return Found::default();
+    };
+    debug!("{} is a sticker message, key: {key}", message.id);
+    let sha = sha3_256(key.as_bytes());
+    let (message, sticker_config) = (message.clone(), config.clone());
+    match ASYNC_DB
+        .run(move |db| sticker::find_mars(db, &message, &key, &sticker_config))
+        .await
+    {
+        Ok(origin) => Found {
+            shas: vec![sha],
+            origin: origin.map(|x| (x, config.sticker_prompt.as_str())),
+        },
+        Err(e) => {
+            error!("Error while insert sticker to database: {e:?}");
+            Found::default()
+        }
+    }
+}
+
+/// Record the texts and links of `message`, and find the message they repeat.
+async fn find_text_origin<'a>(message: &Message, config: &'a Arc<Config>) -> Found<'a> {
+    let message_id = message.id;
+    let keys = text::text_keys(message, config);
+    if keys.is_empty() {
+        return Found::default();
+    }
+    debug!("keys of text message {message_id}: {keys:?}");
+    let record = TextRecord::new(message_id.0, Some(message.date.timestamp()));
+    let chat_id = ChatKey::from(message.chat.id);
+    let cutoff = config.retention.cutoff(retention::now());
+    match ASYNC_DB
+        .run(move |db| text::find_mars(db, &chat_id, record, &keys, cutoff))
+        .await
+    {
+        Ok((shas, Some(origin))) => {
+            info!("find mars text or link: {message_id}");
+            Found {
+                shas,
+                origin: Some((origin, &config.mars_prompt)),
+            }
+        }
+        Ok((shas, None)) => Found { shas, origin: None },
+        Err(e) => {
+            error!("Error while insert text hash to database: {e:?}");
+            Found::default()
+        }
+    }
+}
+
+/// Record the media of `message`, and find the message it repeats.
+async fn find_media_origin<'a>(bot: &Bot, message: &Message, config: &'a Arc<Config>) -> Found<'a> {
+    let message_id = message.id;
+    let chat_id = &ChatKey::from(message.chat.id);
+    let cutoff = config.retention.cutoff(retention::now());
+    let Some(media) = Media::from_message(message, config) else {
+        trace!("{} is not a media message", message.id);
+        return Found::default();
+    };
+    let file_id = media.file.id.as_str();
+    let unique_id = media.file.unique_id.as_str();
+    debug!(
+        "file_id: {}, unique_id: {}, size: {}",
+        file_id, unique_id, media.file.size
+    );
+
+    // a forwarded or re-sent file has the same `file_unique_id`, so it need not
+    // be downloaded.
+    let recorded = {
+        let (chat_id, unique_id) = (chat_id.clone(), unique_id.to_owned());
+        ASYNC_DB
+            .run(move |db| find_by_unique_id(db, &chat_id, &unique_id, message_id.0, cutoff))
+            .await
+    };
+    match recorded {
+        Ok(Some(image)) => {
+            debug!("find recorded file_unique_id: {unique_id}");
+            return Found {
+                shas: vec![image.sha],
+                origin: Some((image.id, &config.mars_prompt)),
+            };
+        }
+        Ok(None) => {}
+        Err(e) => error!("Error while query file_unique_id from database: {e:?}"),
+    }
+
+    let item = match media.download_and_hash(bot, message_id.0, config).await {
+        Ok(Some(x)) => {
+            let x = x.with_time(Some(message.date.timestamp()));
+            debug!(
+                "calculate hash for file {file_id}: `{}`, phash: {:?}, frames: {:?}",
+                hex::encode(&x.sha),
+                x.phash,
+                x.frames
+            );
+            x
+        }
+        Err(err) => {
+            error!("hashing file `{file_id}`: {err:?}");
+            return Found::default();
+        }
+        Ok(None) => {
+            warn!("file `{file_id}` exceed size limit, do not record");
+            return Found::default();
+        }
+    };
+
+    let result = {
+        let (chat_id, unique_id, item) = (chat_id.clone(), unique_id.to_owned(), item.clone());
+        let config = config.clone();
+        ASYNC_DB
+            .run(move |db| {
+                let result = find_mars(db, &chat_id, &item, &config);
+                if let Err(e) = db.insert_unique_id(&chat_id, &unique_id, &item.sha) {
+                    error!("Error while insert file_unique_id to database: {e:?}");
+                }
+                result
+            })
+            .await
+    };
+    match result {
+        Ok(Some(image)) => {
+            info!("find mars file: {file_id}");
+            Found {
+                shas: vec![image.sha],
+                origin: Some((image.id, &config.mars_prompt)),
+            }
+        }
+        Ok(None) => Found {
+            shas: vec![item.sha],
+            origin: None,
+        },
+        Err(e) => {
+            error!("Error while insert hash to database: {e:?}");
+            Found::default()
+        }
+    }
+}
+
+/// Find the unexpired record of a file posted by another message, by its
+/// `file_unique_id`.
+fn find_by_unique_id<D: DbOperation + ?Sized>(
+    db: &D,
+    chat_id: &ChatKey,
+    unique_id: &str,
+    message_id: i32,
+    cutoff: Option<i64>,
+) -> anyhow::Result<Option<MarsImage>> {
+    Ok(db
+        .query_by_unique_id(chat_id, unique_id)?
+        .filter(|x| x.id != message_id && !is_expired(x.time, cutoff)))
+}
+
+/// The most links listed by `{links}`, to keep the reply short.
+const MAX_LINKS: usize = 10;
+
+/// Answer `message`, which repeats message `origin_id`, with `prompt` by the
+/// `config` of the chat. `occurrences` are all posts of the repeated
+/// fingerprint.
+async fn answer_mars(
+    bot: &Bot,
+    message: &Message,
+    origin_id: i32,
+    occurrences: &[Occurrence],
+    prompt: &str,
+    config: &Config,
+) {
+    let mut earlier = occurrences
+        .iter()
+        .map(|x| x.id)
+        .filter(|&x| x < message.id.0)
+        .collect::<Vec<_>>();
+    // records of older versions have no occurrences
+    if let Err(index) = earlier.binary_search(&origin_id) {
+        earlier.insert(index, origin_id);
+    }
+    let urls = earlier
+        .into_iter()
+        .map(|x| msg_url(message.chat.invite_link(), message.chat.id.0, x))
+        .collect::<Vec<_>>();
+    info!("mars message {}, earlier posts: {urls:?}", message.id);
+    let text = mars_text(prompt, &urls);
+    respond::respond(bot, message, &[message.id], text, config).await;
+}
+
+/// Fill the Mars prompt with the urls of earlier posts. `{}` and `{first_url}`
+/// are the first post, `{last_url}` is the last one, `{count}` is the number
+/// of posts including the new one, and `{links}` lists the last `MAX_LINKS`
+/// posts.
+#[allow(clippy::literal_string_with_formatting_args)]
+fn mars_text(prompt: &str, urls: &[String]) -> String {
+    let first_url = urls.first().map_or("", String::as_str);
+    let links = urls
+        .iter()
+        .enumerate()
+        .skip(urls.len().saturating_sub(MAX_LINKS))
+        .map(|(index, url)| format!("[{}]({url})", index + 1))
+        .collect::<Vec<_>>()
+        .join(", ");
+    prompt
+        .replace("{count}", &(urls.len() + 1).to_string())
+        .replace("{first_url}", first_url)
+        .replace("{last_url}", urls.last().map_or("", String::as_str))
+        .replace("{links}", &links)
+        .format(&[first_url])
+}
+
+/// Insert a record to the chat table, and find the record it repeats.
+///
+/// The exact sha is checked first. If there is no exact match, the image with
+/// the closest perceptual hash within `similarity_threshold` is returned; a
+/// video is compared by its frames. Records from the same message, and
+/// expired records, are never reported. Records evicted by the backend are
+/// removed from the index.
+fn find_mars<D: DbOperation + ?Sized>(
+    db: &D,
+    chat_id: &ChatKey,
+    item: &MarsImage,
+    config: &Config,
+) -> anyhow::Result<Option<MarsImage>> {
+    let cutoff = config.retention.cutoff(retention::now());
+    let (existing, evicted) = retention::insert_or_get_existing(db, chat_id, item.clone(), cutoff)?;
+    db.index().remove(chat_id, &evicted);
+    if existing.is_some() {
+        return Ok(existing);
+    }
+    let threshold = config.similarity_threshold;
+    let similar = if item.kind.is_video() {
+        db.index()
+            .find_similar_video(
+                db,
+                chat_id,
+                item,
+                threshold,
+                config.video_match_ratio,
+                cutoff,
+            )?
+            .map(|(ratio, x)| {
+                debug!("find similar video {}, frame match ratio {ratio}", x.id);
+                x
+            })
+    } else if let Some(phash) = item.phash {
+        db.index()
+            .find_nearest(db, chat_id, phash, threshold, item.id, cutoff)?
+            .map(|(distance, x)| {
+                debug!("find similar image {}, distance {distance}", x.id);
+                x
+            })
+    } else {
+        None
+    };
+    db.index().insert(db, chat_id, item)?;
+    Ok(similar)
+}
+
+/// How often expired records are purged.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Purge expired records of all chats every `PRUNE_INTERVAL`.
+async fn prune_periodically() {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match ASYNC_DB
+            .run(|db| retention::prune(db, retention::now()))
+            .await
+        {
+            Ok(pruned) => {
+                for (table, purged) in pruned {
+                    info!("purged {purged} expired records of chat {table}");
+                }
+            }
+            Err(e) => error!("purge expired records failed: {e:?}"),
+        }
+    }
+}
+
+pub async fn run(cli: Cli) {
+    let bot = cli
+        .token
+        .or_else(|| CONFIG.get_or_init_default().token.clone())
+        .map_or_else(Bot::from_env, Bot::new);
+
+    match DB.index().rebuild(&**DB) {
+        Ok(count) => info!("perceptual hash index rebuilt, {count} images"),
+        Err(e) => error!("rebuild perceptual hash index failed: {e:?}"),
+    }
+
+    tokio::spawn(prune_periodically());
+    tokio::spawn(ttl::delete_periodically(bot.clone()));
+
+    if let Err(e) = bot.set_my_commands(Command::bot_commands()).await {
+        error!("set the command list failed: {e:?}");
+    }
+
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .branch(
+                    dptree::entry()
+                        .filter_command::<Command>()
+                        .endpoint(command::answer),
+                )
+                .endpoint(|bot: Bot, msg: Message| async move {
+                    Box::pin(handler(Box::leak(Box::new(bot)), msg)).await;
+                    respond(())
+                }),
+        )
+        .branch(Update::filter_message_reaction_updated().endpoint(command::react));
+    Box::pin(
+        Dispatcher::builder(bot, handler)
+            .default_handler(|_| async {})
+            .enable_ctrlc_handler()
+            .build()
+            .dispatch(),
+    )
+    .await;
+
+    if let Err(e) = DB.close() {
+        error!("close database failed: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDb;
+
+    #[test]
+    fn test_merge_found() {
+        let mut found = Found {
+            shas: vec![vec![1]],
+            origin: None,
+        };
+        found.merge(Found {
+            shas: vec![vec![2]],
+            origin: Some((2, "text")),
+        });
+        found.merge(Found {
+            shas: vec![vec![3]],
+            origin: Some((3, "media")),
+        });
+        found.merge(Found::default());
+        // every fingerprint is an occurrence, the first origin wins
+        assert_eq!(found.shas, vec![vec![2], vec![1], vec![3]]);
+        assert_eq!(found.origin, Some((2, "text")));
+    }
+
+    #[test]
+    fn test_find_mars_forgets_evicted() {
+        let db = MemoryDb::new(1, None).unwrap();
+        let config = Config::default();
+        let chat = ChatKey::from(123);
+        let old = MarsImage::new(1, [1]).with_phash(Some(7));
+        assert_eq!(find_mars(&db, &chat, &old, &config).unwrap(), None);
+        let new = MarsImage::new(2, [2]).with_phash(Some(!7));
+        assert_eq!(find_mars(&db, &chat, &new, &config).unwrap(), None);
+        // the old image is evicted, so a similar one does not repeat it
+        let similar = MarsImage::new(3, [3]).with_phash(Some(7));
+        assert_eq!(find_mars(&db, &chat, &similar, &config).unwrap(), None);
+    }
+
+    #[test]
+    fn test_mars_text() {
+        let urls = ["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        assert_eq!(
+            mars_text("{count} times, [first]({}), last {last_url}", &urls),
+            "4 times, [first](a), last c"
+        );
+        assert_eq!(
+            mars_text("{first_url}: {links}", &urls),
+            "a: [1](a), [2](b), [3](c)"
+        );
+        let urls = (0..12).map(|x| x.to_string()).collect::<Vec<_>>();
+        assert!(mars_text("{links}", &urls).starts_with("[3](2), "));
+    }
+}
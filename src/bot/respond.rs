@@ -0,0 +1,130 @@
+//! Answer a Mars by the `response` of the chat: reply the prompt, react to
+//! the reposts, forward them to the moderation chat, delete them, or only log
+//! it. Modes are combined in this order, so reposts are forwarded before they
+//! are deleted. Replies are rate limited, and requests limited by Telegram are
+//! retried.
+
+use log::{error, info, warn};
+use teloxide::{
+    prelude::*,
+    types::{MessageId, ParseMode, ReactionType, ReplyParameters},
+};
+
+use super::{
+    limit::{self, send_retrying},
+    ttl,
+};
+use crate::config::{Config, ResponseMode};
+
+/// Answer the Mars `reposts` by the `config` of the chat. `text` is the
+/// filled prompt, replied to `message`.
+pub async fn respond(
+    bot: &Bot,
+    message: &Message,
+    reposts: &[MessageId],
+    text: String,
+    config: &Config,
+) {
+    let response = &config.response;
+    let chat = message.chat.id;
+    if response.contains(ResponseMode::Log) {
+        info!("Mars of messages {reposts:?} in chat {chat}: {text}");
+    }
+    if response.contains(ResponseMode::React) {
+        for &id in reposts {
+            send_retrying(
+                bot.set_message_reaction(chat, id)
+                    .reaction(vec![reaction(&config.mars_reaction)]),
+            )
+            .await
+            .log_on_error()
+            .await;
+        }
+    }
+    if response.contains(ResponseMode::Forward) {
+        match config.mod_chat {
+            Some(mod_chat) => forward(bot, chat, ChatId(mod_chat), reposts, &text).await,
+            None => warn!("response `forward` of chat {chat} needs `mod_chat`"),
+        }
+    }
+    if response.contains(ResponseMode::Reply) {
+        if limit::allow(message, config) {
+            send_reply(bot, message, text, config).await;
+        } else {
+            limit::suppress(bot, chat, config);
+        }
+    }
+    if response.contains(ResponseMode::Delete) {
+        send_retrying(bot.delete_messages(chat, reposts.to_vec()))
+            .await
+            .log_on_error()
+            .await;
+    }
+}
+
+/// A reaction emoji, or a custom emoji given by its id.
+fn reaction(value: &str) -> ReactionType {
+    if !value.is_empty() && value.bytes().all(|x| x.is_ascii_digit()) {
+        ReactionType::CustomEmoji {
+            custom_emoji_id: value.to_owned(),
+        }
+    } else {
+        ReactionType::Emoji {
+            emoji: value.to_owned(),
+        }
+    }
+}
+
+/// Forward `reposts` to `mod_chat`, and reply `text` to them there.
+async fn forward(bot: &Bot, chat: ChatId, mod_chat: ChatId, reposts: &[MessageId], text: &str) {
+    let forwarded =
+        match send_retrying(bot.forward_messages(mod_chat, chat, reposts.to_vec())).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("forward Mars of chat {chat} to {mod_chat} failed: {e:?}");
+                return;
+            }
+        };
+    let mut request = bot
+        .send_message(mod_chat, text)
+        .parse_mode(ParseMode::MarkdownV2);
+    if let Some(first) = forwarded.first() {
+        request = request.reply_parameters(ReplyParameters::new(*first));
+    }
+    send_retrying(request).await.log_on_error().await;
+}
+
+/// Reply `text` in `MarkdownV2` to `message`, and schedule deleting the reply
+/// after `reply_ttl`. It is still sent if the message is deleted meanwhile.
+async fn send_reply(bot: &Bot, message: &Message, text: String, config: &Config) {
+    // .escape_telegram_markdown_text()
+    let request = bot
+        .send_message(message.chat.id, text)
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_parameters(ReplyParameters::new(message.id).allow_sending_without_reply());
+    match send_retrying(request).await {
+        Ok(reply) => ttl::schedule(message.chat.id.into(), reply.id, config).await,
+        Err(e) => error!("send Mars reply failed: {e:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reaction() {
+        assert_eq!(
+            reaction("🔥"),
+            ReactionType::Emoji {
+                emoji: "🔥".to_owned()
+            }
+        );
+        assert_eq!(
+            reaction("5368324170671202286"),
+            ReactionType::CustomEmoji {
+                custom_emoji_id: "5368324170671202286".to_owned()
+            }
+        );
+    }
+}
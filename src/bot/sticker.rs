@@ -0,0 +1,138 @@
+//! Repeat detection of stickers and custom emojis.
+//!
+//! A sticker is identified by its `file_unique_id` (or its set name if
+//! `sticker_match_set` is set), so nothing is downloaded.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use teloxide::{prelude::*, types::MessageEntityKind};
+
+use crate::{
+    config::{Config, StickerMode},
+    db::{retention, ChatKey, DbOperation, MarsImage, MediaKind},
+    hash::sha3_256,
+};
+
+static BURSTS: LazyLock<Mutex<BurstCounter>> = LazyLock::new(Mutex::default);
+
+/// The key of a sticker, or a message only consisting of custom emojis.
+pub fn sticker_key(message: &Message, match_set: bool) -> Option<String> {
+    if let Some(sticker) = message.sticker() {
+        return Some(match (&sticker.set_name, match_set) {
+            (Some(set_name), true) => format!("set:{set_name}"),
+            _ => format!("sticker:{}", sticker.file.unique_id),
+        });
+    }
+    let text = message.text()?;
+    let mut ids = vec![];
+    let mut length = 0;
+    for entity in message.entities()? {
+        let MessageEntityKind::CustomEmoji { custom_emoji_id } = &entity.kind else {
+            return None;
+        };
+        ids.push(custom_emoji_id.as_str());
+        length += entity.length;
+    }
+    // entity lengths are counted in utf-16 code units
+    let text_length = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(char::len_utf16)
+        .sum::<usize>();
+    (!ids.is_empty() && length == text_length).then(|| format!("emoji:{}", ids.join(",")))
+}
+
+/// Record the sticker of `message`, and find the message it repeats according
+/// to `sticker_mode` of the `config` of the chat.
+pub fn find_mars<D: DbOperation + ?Sized>(
+    db: &D,
+    message: &Message,
+    key: &str,
+    config: &Config,
+) -> Result<Option<i32>> {
+    let chat_id = ChatKey::from(message.chat.id);
+    let item = MarsImage::new(message.id.0, sha3_256(key.as_bytes()))
+        .with_kind(MediaKind::Sticker)
+        .with_time(Some(message.date.timestamp()));
+    // the first post is always recorded, to be linked in `repeat` mode
+    let cutoff = config.retention.cutoff(retention::now());
+    let (first, evicted) = retention::insert_or_get_existing(db, &chat_id, item, cutoff)?;
+    db.index().remove(&chat_id, &evicted);
+    Ok(match config.sticker_mode {
+        StickerMode::Repeat => first.map(|x| x.id),
+        StickerMode::Burst => BURSTS.lock().unwrap().record(
+            (chat_id.into(), key.to_owned()),
+            message.date.timestamp(),
+            message.id.0,
+            config.sticker_burst_count,
+            config.sticker_burst_minutes.saturating_mul(60),
+        ),
+    })
+}
+
+/// Sliding windows of recent posts of each sticker in each chat. They are kept
+/// in memory only, as windows are short.
+#[derive(Debug, Default)]
+struct BurstCounter {
+    /// `(chat, key)` to `(timestamp, message id)` of posts in the window
+    windows: HashMap<(String, String), VecDeque<(i64, i32)>>,
+}
+
+impl BurstCounter {
+    /// Record a post at `time`. If there are `count` posts in the last `window`
+    /// seconds, returns the id of the first one and clears the window.
+    fn record(
+        &mut self,
+        key: (String, String),
+        time: i64,
+        id: i32,
+        count: usize,
+        window: u64,
+    ) -> Option<i32> {
+        let start = time.saturating_sub_unsigned(window);
+        if self.windows.len() > 10_000 {
+            self.windows
+                .retain(|_, posts| posts.back().is_some_and(|x| x.0 > start));
+        }
+        let posts = self.windows.entry(key).or_default();
+        while posts.front().is_some_and(|x| x.0 <= start) {
+            posts.pop_front();
+        }
+        posts.push_back((time, id));
+        if posts.len() < count.max(2) {
+            return None;
+        }
+        let first = posts.front().map(|x| x.1);
+        posts.clear();
+        first
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_counter() {
+        let mut counter = BurstCounter::default();
+        let key = || ("chat".to_owned(), "sticker".to_owned());
+        assert_eq!(counter.record(key(), 0, 1, 3, 60), None);
+        assert_eq!(counter.record(key(), 10, 2, 3, 60), None);
+        // a different sticker is counted separately
+        assert_eq!(
+            counter.record(("chat".to_owned(), "other".to_owned()), 20, 3, 3, 60),
+            None
+        );
+        assert_eq!(counter.record(key(), 30, 4, 3, 60), Some(1));
+        // the window is cleared after a Mars
+        assert_eq!(counter.record(key(), 40, 5, 3, 60), None);
+        assert_eq!(counter.record(key(), 50, 6, 3, 60), None);
+        // post 5 is out of the window
+        assert_eq!(counter.record(key(), 101, 7, 3, 60), None);
+        assert_eq!(counter.record(key(), 102, 8, 3, 60), Some(6));
+    }
+}
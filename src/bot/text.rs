@@ -0,0 +1,78 @@
+//! Repeat detection of links and text messages. Only the hash of each key is
+//! recorded, in a table apart from the images.
+
+use anyhow::Result;
+use teloxide::{prelude::*, types::MessageEntityKind};
+
+use crate::{
+    config::Config,
+    db::{retention, ChatKey, DbOperation, TextRecord},
+    hash::{
+        sha3_256,
+        text::{canonicalize_url, normalize_text},
+    },
+};
+
+/// The keys of the links in the text or caption of `message` if `detect_link`
+/// is set, or the key of its text if there is no link and `detect_text` is set.
+pub fn text_keys(message: &Message, config: &Config) -> Vec<String> {
+    let mut keys = vec![];
+    if config.detect_link {
+        let entities = message
+            .parse_entities()
+            .or_else(|| message.parse_caption_entities())
+            .unwrap_or_default();
+        for entity in &entities {
+            let link = match entity.kind() {
+                MessageEntityKind::Url => entity.text(),
+                MessageEntityKind::TextLink { url } => url.as_str(),
+                _ => continue,
+            };
+            if let Some(key) = canonicalize_url(link).map(|x| format!("url:{x}")) {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+    }
+    if keys.is_empty() && config.detect_text {
+        if let Some(text) = message.text().map(normalize_text) {
+            if text.chars().count() >= config.text_min_length {
+                keys.push(format!("text:{text}"));
+            }
+        }
+    }
+    keys
+}
+
+/// Record all keys of message `record`, and find the first message any of
+/// them repeats. Records inserted before `cutoff` are expired and replaced.
+///
+/// # Returns
+///
+/// The hash of the repeated key with the origin message id, or the hashes of
+/// all keys if nothing is repeated.
+pub fn find_mars<D: DbOperation + ?Sized>(
+    db: &D,
+    chat_id: &ChatKey,
+    record: TextRecord,
+    keys: &[String],
+    cutoff: Option<i64>,
+) -> Result<(Vec<Vec<u8>>, Option<i32>)> {
+    let (mut shas, mut origin) = (vec![], None);
+    for key in keys {
+        let sha = sha3_256(key.as_bytes());
+        let existing = retention::insert_or_get_existing_text(db, chat_id, &sha, record, cutoff)?
+            .map(|x| x.id)
+            .filter(|&x| x != record.id);
+        match (origin, existing) {
+            (None, Some(x)) => {
+                origin = Some(x);
+                shas = vec![sha];
+            }
+            (None, None) => shas.push(sha),
+            (Some(_), _) => {}
+        }
+    }
+    Ok((shas, origin))
+}
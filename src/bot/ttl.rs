@@ -0,0 +1,98 @@
+//! Deletion of Mars replies after `reply_ttl`. Deletions are scheduled in the
+//! database, so replies sent before a restart are still deleted.
+
+use std::time::Duration;
+
+use log::{error, info, warn};
+use teloxide::{prelude::*, types::MessageId, RequestError};
+
+use crate::{
+    config::Config,
+    db::{retention, ChatKey, PendingDeletion, ASYNC_DB},
+};
+
+/// How often due deletions are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// The longest wait after failed deletions.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Schedule deleting the reply `message_id` of the bot, if `reply_ttl` is set
+/// in the `config` of the chat.
+pub async fn schedule(chat: ChatKey, message_id: MessageId, config: &Config) {
+    if config.reply_ttl == 0 {
+        return;
+    }
+    let due = retention::now().saturating_add_unsigned(config.reply_ttl);
+    let deletion = PendingDeletion::new(chat, message_id.0, due);
+    if let Err(e) = ASYNC_DB
+        .run(move |db| db.schedule_deletion(&deletion))
+        .await
+    {
+        error!("schedule deleting reply failed: {e:?}");
+    }
+}
+
+/// Delete due replies every `CHECK_INTERVAL`, waiting longer while Telegram
+/// asks to retry later or the network fails.
+pub async fn delete_periodically(bot: Bot) {
+    let mut wait = CHECK_INTERVAL;
+    loop {
+        tokio::time::sleep(wait).await;
+        wait = delete_due(&bot, wait).await;
+    }
+}
+
+/// Delete due replies. `wait` is the last wait.
+///
+/// # Returns
+///
+/// How long to wait before the next check.
+async fn delete_due(bot: &Bot, wait: Duration) -> Duration {
+    let due = match ASYNC_DB
+        .run(|db| db.query_due_deletions(retention::now()))
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!("query due deletions failed: {e:?}");
+            return backoff(wait);
+        }
+    };
+    for deletion in due {
+        let (chat, id) = (ChatId(deletion.chat.id()), MessageId(deletion.message_id));
+        match bot.delete_message(chat, id).await {
+            Ok(_) => info!("deleted reply {} of chat {chat}", id.0),
+            Err(RequestError::RetryAfter(seconds)) => {
+                warn!("deleting replies is limited, retry after {seconds}");
+                return seconds.duration().max(CHECK_INTERVAL);
+            }
+            Err(e @ (RequestError::Network(_) | RequestError::Io(_))) => {
+                warn!("deleting reply {} of chat {chat} failed: {e:?}", id.0);
+                return backoff(wait);
+            }
+            // e.g. the reply is already deleted, or too old to delete
+            Err(e) => warn!("give up deleting reply {} of chat {chat}: {e:?}", id.0),
+        }
+        if let Err(e) = ASYNC_DB.run(move |db| db.remove_deletion(&deletion)).await {
+            error!("remove scheduled deletion failed: {e:?}");
+            return backoff(wait);
+        }
+    }
+    CHECK_INTERVAL
+}
+
+/// Double the last wait, up to `MAX_BACKOFF`.
+fn backoff(wait: Duration) -> Duration {
+    (wait * 2).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(CHECK_INTERVAL), Duration::from_secs(10));
+        assert_eq!(backoff(Duration::from_secs(200)), MAX_BACKOFF);
+    }
+}
@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 
+use crate::db::{convert::DbSpec, dump::Format, Backend, ChatKey};
+
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None, after_help = r#"Examples:
 "#)]
@@ -13,6 +15,9 @@ pub struct Cli {
     /// Bot token
     #[arg(short, long, global = true)]
     pub token: Option<String>,
+    /// Database backend, overrides the config file
+    #[arg(short, long, global = true)]
+    pub backend: Option<Backend>,
     /// Operations
     #[command(subcommand)]
     pub command: Option<SubCommand>,
@@ -22,8 +27,75 @@ pub struct Cli {
 pub enum SubCommand {
     /// delete all Mars record from a chat
     #[clap(alias("d"))]
-    Delete { chat_id: String },
-    /// Export default config.
+    Delete { chat_id: ChatKey },
+    /// Keep only one record per message in a chat, or in all chats if
+    /// `chat_id` is missing.
+    ///
+    /// Older versions recorded every size of a photo. Only one size is
+    /// recorded now, which is not known for old records, so reposts of old
+    /// photos may not be found after compacting.
+    #[clap(alias("c"))]
+    Compact { chat_id: Option<ChatKey> },
+    /// Purge records older than the `retention` of their chat. This is also
+    /// done hourly by the bot.
+    #[clap(alias("p"))]
+    Prune,
+    /// Upgrade the database to the latest schema version. This is also done
+    /// on startup.
+    #[clap(alias("m"))]
+    Migrate {
+        /// Only show the pending migrations.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy all records to another database, possibly of another backend.
+    ///
+    /// Databases are given as `<backend>:<path>`, where backend is `sled`,
+    /// `sqlite` or `memory`, whose path is the dir of its snapshot. The source
+    /// database is only read, so it must be migrated first. The target
+    /// database must be empty.
+    Convert {
+        /// The source database, e.g. `sled:~/.local/mars-bot/db`
+        #[arg(long)]
+        from: DbSpec,
+        /// The target database, e.g. `sqlite:~/.local/mars-bot/mars.sqlite`
+        #[arg(long)]
+        to: DbSpec,
+    },
+    /// Record the photos of a chat history exported by Telegram Desktop, with
+    /// their original message ids.
+    Backfill {
+        chat_id: ChatKey,
+        /// The export directory, containing `result.json`
+        export_dir: PathBuf,
+    },
+    /// Show the settings of a chat, or change them by `key=value` pairs, e.g.
+    /// `retention=30d`. An empty value, e.g. `retention=`, unsets a setting,
+    /// so the config file is followed again.
+    ChatConfig {
+        chat_id: ChatKey,
+        pairs: Vec<String>,
+    },
+    /// Export all records and settings of a chat, or the default config file
+    /// if `chat_id` is missing.
     #[clap(alias("e"))]
-    Export,
+    Export {
+        chat_id: Option<ChatKey>,
+        /// `jsonl` or `csv`, guessed by the extension of `output` by default
+        #[arg(short, long, requires = "chat_id")]
+        format: Option<Format>,
+        /// Write records to the file instead of stdout
+        #[arg(short, long, requires = "chat_id")]
+        output: Option<PathBuf>,
+    },
+    /// Import records exported by `export <chat_id>` to a chat. Existing
+    /// records of the same file or text are kept.
+    #[clap(alias("i"))]
+    Import {
+        chat_id: ChatKey,
+        file: PathBuf,
+        /// `jsonl` or `csv`, guessed by the file extension by default
+        #[arg(short, long)]
+        format: Option<Format>,
+    },
 }
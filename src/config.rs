@@ -1,26 +1,141 @@
-use std::{path::PathBuf, sync::OnceLock};
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr, sync::OnceLock};
 
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use teloxide::types::PhotoSize;
 
-use crate::db::db_path;
+use crate::{
+    db::{db_path, retention::Retention, Backend, ChatKey, DbOperation},
+    hash::PerceptualHash,
+    utils::OnceLockDefaultInit,
+};
 
 pub static CONFIG: OnceLock<Config> = OnceLock::new();
 
+/// The largest `similarity_threshold`. Perceptual hashes of unrelated images
+/// differ in about half of the 64 bits, so a larger one matches them.
+pub const MAX_SIMILARITY_THRESHOLD: u32 = 16;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
     /// only reply mars warning if the message is from a channel.
     pub only_mars_for_channel_message: bool,
     pub token: Option<String>,
     /// allowed max file size in bytes.
     pub max_file_size: u32,
+    /// Which size of a photo is downloaded and hashed: `smallest`, `largest`
+    /// or `closest` to `photo_target_resolution`.
+    pub photo_size: PhotoSizeSelection,
+    /// The target length of the longer side of a photo in pixels, used by
+    /// `photo_size = "closest"`.
+    pub photo_target_resolution: u32,
     /// Mars prompt. The origin message link will be filled in `{}`.
+    /// Also available: `{count}` (the number of posts including this one),
+    /// `{first_url}`, `{last_url}` (the last earlier post) and `{links}` (links
+    /// to the last 10 earlier posts).
     ///
     /// The prompt should be formatted as markdown. Additional escape rule: <https://core.telegram.org/bots/api#formatting-options>.
     /// If you find some error like `Character '.' is reserved and must be
     /// escaped...`, please escape them.
     pub mars_prompt: String,
+    /// The database backend: `sled`, `sqlite` or `memory`. Only backends of
+    /// enabled cargo features can be used.
+    pub backend: Backend,
     /// The database path. If missing, it will be create.
     pub db_dir: PathBuf,
+    /// The most images and texts kept in each chat by the `memory` backend,
+    /// the least recently used ones are forgotten. `0` means no limit.
+    pub memory_capacity: usize,
+    /// Save the records of the `memory` backend to `db_dir` on shutdown, and
+    /// load them on startup.
+    pub memory_snapshot: bool,
+    /// How long records are kept, e.g. `30d` (units: `s`, `m`, `h`, `d`,
+    /// `w`). Expired records are ignored, and purged hourly and by `mars-bot
+    /// prune`. `never` keeps them forever.
+    pub retention: Retention,
+    /// The perceptual hash algorithm used to find re-compressed or resized
+    /// images: `none`, `ahash`, `dhash` or `phash`.
+    pub perceptual_hash: PerceptualHash,
+    /// Two images are treated as the same one if the hamming distance of their
+    /// perceptual hashes is not greater than this value (0 ~ 64).
+    pub similarity_threshold: u32,
+    /// Also detect repeated videos, animations (GIF) and video notes.
+    pub detect_video: bool,
+    /// How many frames of a video are hashed. Frames of videos other than GIF
+    /// are extracted by `ffmpeg`, which needs the `video` feature, otherwise
+    /// only the thumbnail is hashed.
+    pub video_frames: usize,
+    /// Two videos are treated as the same one if at least this fraction of
+    /// frames are similar (0.0 ~ 1.0).
+    pub video_match_ratio: f32,
+    /// Also detect repeated stickers and messages only consisting of custom
+    /// emojis.
+    pub detect_sticker: bool,
+    /// `repeat`: a sticker posted ever before is a Mars. `burst`: a sticker
+    /// posted `sticker_burst_count` times within `sticker_burst_minutes` is a
+    /// Mars.
+    pub sticker_mode: StickerMode,
+    pub sticker_burst_count: usize,
+    pub sticker_burst_minutes: u64,
+    /// Treat all stickers of a sticker set as the same one.
+    pub sticker_match_set: bool,
+    /// Mars prompt for stickers, in the same format as `mars_prompt`.
+    pub sticker_prompt: String,
+    /// Detect repeated links in messages and captions. Links are canonicalised
+    /// first, e.g. tracking parameters are removed.
+    pub detect_link: bool,
+    /// Detect repeated text messages without links.
+    pub detect_text: bool,
+    /// Text messages shorter than this (in characters) are ignored.
+    pub text_min_length: usize,
+    /// Items of an album are replied together, after no item arrives for this
+    /// long.
+    pub album_wait_millis: u64,
+    /// Only reply an album if all of its items are marsed.
+    pub album_mars_all: bool,
+    /// Mars prompt for albums. `{}` is replaced by the links to the origin of
+    /// each marsed item.
+    pub album_prompt: String,
+    /// An administrator reacting to a message with this emoji, e.g. `"🗿"`,
+    /// forgets the images and texts first posted by the message. The bot must
+    /// be an administrator to see reactions. Empty disables it.
+    pub forget_reaction: String,
+    /// How a Mars is answered, several modes joined by `,`, e.g.
+    /// `react,forward`. `reply`: reply the prompt. `react`: react to the repost
+    /// with `mars_reaction`. `forward`: forward the repost to `mod_chat` with
+    /// the prompt. `delete`: delete the repost, the bot must be an
+    /// administrator allowed to delete messages. `log`: only log it.
+    pub response: Response,
+    /// The reaction of `react`, an emoji allowed by Telegram (e.g. `"🔥"`), or
+    /// the id of a custom emoji.
+    pub mars_reaction: String,
+    /// The chat id which `forward` sends reposts to, e.g. `-100123`. It is
+    /// not a setting of chats, so admins of a chat cannot send its reposts to
+    /// any other chat.
+    pub mod_chat: Option<i64>,
+    /// Delete the Mars reply of the bot after this many seconds. `0` keeps
+    /// it.
+    pub reply_ttl: u64,
+    /// Mars replies allowed in a chat: `burst` at once, refilled by
+    /// `per_minute`. `burst = 0` removes the limit.
+    pub chat_reply_limit: RateLimit,
+    /// Mars replies allowed to each sender in a chat, in the same form as
+    /// `chat_reply_limit`.
+    pub user_reply_limit: RateLimit,
+    /// Suppressed Mars replies of a chat are counted for this many minutes,
+    /// then summarized in one message.
+    pub summary_minutes: u64,
+    /// The summary of suppressed replies, in plain text. `{count}` is the
+    /// number of suppressed replies and `{minutes}` is `summary_minutes`.
+    pub summary_prompt: String,
+    /// `retention` of some chats of older versions, keyed by chat id. It is
+    /// moved to the settings of the chats on startup, see
+    /// [`migrate_chat_retention`].
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub chat_retention: HashMap<String, Retention>,
 }
 
 impl Default for Config {
@@ -28,9 +143,536 @@ impl Default for Config {
         Self {
             max_file_size: 10 * 1024 * 1024, // 10MB
             only_mars_for_channel_message: false,
+            photo_size: PhotoSizeSelection::default(),
+            photo_target_resolution: 320,
             token: None,
             mars_prompt: "You Marsed\\! [Origin message]({})".to_string(),
+            backend: Backend::default(),
             db_dir: db_path(),
+            memory_capacity: 0,
+            memory_snapshot: false,
+            retention: Retention::default(),
+            perceptual_hash: PerceptualHash::default(),
+            similarity_threshold: 4,
+            detect_video: false,
+            video_frames: 5,
+            video_match_ratio: 0.6,
+            detect_sticker: false,
+            sticker_mode: StickerMode::default(),
+            sticker_burst_count: 3,
+            sticker_burst_minutes: 10,
+            sticker_match_set: false,
+            sticker_prompt: "Stop spamming the same sticker\\! [First one]({})".to_string(),
+            detect_link: false,
+            detect_text: false,
+            text_min_length: 20,
+            album_wait_millis: 1500,
+            album_mars_all: false,
+            album_prompt: "Album Marsed\\! Origins of items: {}".to_string(),
+            forget_reaction: String::new(),
+            response: Response::default(),
+            mars_reaction: "🔥".to_string(),
+            mod_chat: None,
+            reply_ttl: 0,
+            chat_reply_limit: RateLimit {
+                burst: 5,
+                per_minute: 10,
+            },
+            user_reply_limit: RateLimit {
+                burst: 3,
+                per_minute: 3,
+            },
+            summary_minutes: 5,
+            summary_prompt: "{count} more reposts in the last {minutes} minutes.".to_string(),
+            chat_retention: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Check the ranges of numeric options.
+    pub fn validate(&self) -> Result<()> {
+        ChatSettings {
+            max_file_size: Some(self.max_file_size),
+            similarity_threshold: Some(self.similarity_threshold),
+            text_min_length: Some(self.text_min_length),
+            ..ChatSettings::default()
+        }
+        .validate()?;
+        ensure!(
+            self.video_frames > 0,
+            "invalid value `0` of `video_frames`, expect a positive number"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.video_match_ratio),
+            "invalid value `{}` of `video_match_ratio`, expect 0.0 ~ 1.0",
+            self.video_match_ratio
+        );
+        Ok(())
+    }
+
+    /// The config of a chat, overridden by its `settings`.
+    pub fn for_chat(&self, settings: &ChatSettings) -> Self {
+        let mut config = self.clone();
+        macro_rules! apply {
+            ($($field:ident),*) => {
+                $(if let Some(value) = &settings.$field {
+                    config.$field.clone_from(value);
+                })*
+            };
+        }
+        apply!(
+            only_mars_for_channel_message,
+            max_file_size,
+            mars_prompt,
+            retention,
+            similarity_threshold,
+            detect_video,
+            detect_sticker,
+            sticker_mode,
+            sticker_prompt,
+            detect_link,
+            detect_text,
+            text_min_length,
+            album_mars_all,
+            album_prompt,
+            forget_reaction,
+            response,
+            mars_reaction,
+            reply_ttl
+        );
+        config
+    }
+}
+
+/// The config of `chat`, overridden by its settings in `db`.
+pub fn chat_config<D: DbOperation + ?Sized>(db: &D, chat: &ChatKey) -> Result<Config> {
+    Ok(CONFIG
+        .get_or_init_default()
+        .for_chat(&db.query_settings(chat)?))
+}
+
+/// Move `chat_retention` of older versions to the settings of the chats. A
+/// retention already set for a chat is kept.
+///
+/// # Returns
+///
+/// The chats whose settings are changed.
+pub fn migrate_chat_retention<D: DbOperation + ?Sized>(
+    db: &D,
+    config: &Config,
+) -> Result<Vec<ChatKey>> {
+    let mut migrated = vec![];
+    for (chat, retention) in &config.chat_retention {
+        let chat = chat.parse::<ChatKey>()?;
+        let mut settings = db.query_settings(&chat)?;
+        if settings.retention.is_none() {
+            settings.retention = Some(*retention);
+            db.save_settings(&chat, &settings)?;
+            migrated.push(chat);
+        }
+    }
+    Ok(migrated)
+}
+
+/// The settings of a chat, kept in the database and edited by `/mars_set` or
+/// `mars-bot chat-config`. Each field set overrides the one of [`Config`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatSettings {
+    pub only_mars_for_channel_message: Option<bool>,
+    pub max_file_size: Option<u32>,
+    pub mars_prompt: Option<String>,
+    pub retention: Option<Retention>,
+    pub similarity_threshold: Option<u32>,
+    pub detect_video: Option<bool>,
+    pub detect_sticker: Option<bool>,
+    pub sticker_mode: Option<StickerMode>,
+    pub sticker_prompt: Option<String>,
+    pub detect_link: Option<bool>,
+    pub detect_text: Option<bool>,
+    pub text_min_length: Option<usize>,
+    pub album_mars_all: Option<bool>,
+    pub album_prompt: Option<String>,
+    pub forget_reaction: Option<String>,
+    pub response: Option<Response>,
+    pub mars_reaction: Option<String>,
+    pub reply_ttl: Option<u64>,
+}
+
+impl ChatSettings {
+    fn fields(&self) -> Map<String, Value> {
+        match serde_json::to_value(self).expect("settings are always serializable") {
+            Value::Object(fields) => fields,
+            _ => unreachable!("settings are a struct"),
+        }
+    }
+
+    /// The names of all settings.
+    pub fn keys() -> Vec<String> {
+        Self::default().fields().into_iter().map(|x| x.0).collect()
+    }
+
+    /// Set the setting `key` from text, e.g. `true`, `30d` or a prompt. An
+    /// empty value unsets it, so the config is followed again.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut fields = self.fields();
+        if !fields.contains_key(key) {
+            bail!(
+                "unknown setting `{key}`, expect one of {}",
+                Self::keys().join(", ")
+            );
+        }
+        let candidates = if value.is_empty() {
+            vec![Value::Null]
+        } else {
+            // a value is parsed as json first, so `true` is a bool, but a
+            // prompt may also look like a number
+            serde_json::from_str(value)
+                .into_iter()
+                .filter(|x: &Value| !x.is_null())
+                .chain([Value::String(value.to_owned())])
+                .collect()
+        };
+        for candidate in candidates {
+            fields.insert(key.to_owned(), candidate);
+            if let Ok(settings) = serde_json::from_value::<Self>(Value::Object(fields.clone())) {
+                settings.validate()?;
+                *self = settings;
+                return Ok(());
+            }
+        }
+        bail!("invalid value `{value}` of setting `{key}`")
+    }
+
+    /// Check the ranges of numeric settings.
+    pub fn validate(&self) -> Result<()> {
+        fn check<T: fmt::Display>(
+            key: &str,
+            value: Option<T>,
+            ok: bool,
+            expect: &str,
+        ) -> Result<()> {
+            if let Some(value) = value {
+                ensure!(
+                    ok,
+                    "invalid value `{value}` of setting `{key}`, expect {expect}"
+                );
+            }
+            Ok(())
+        }
+        check(
+            "max_file_size",
+            self.max_file_size,
+            self.max_file_size != Some(0),
+            "a positive size in bytes",
+        )?;
+        check(
+            "similarity_threshold",
+            self.similarity_threshold,
+            self.similarity_threshold <= Some(MAX_SIMILARITY_THRESHOLD),
+            &format!("at most {MAX_SIMILARITY_THRESHOLD}"),
+        )?;
+        check(
+            "text_min_length",
+            self.text_min_length,
+            self.text_min_length != Some(0),
+            "a positive length",
+        )
+    }
+
+    /// Set a setting from `key=value`.
+    pub fn set_pair(&mut self, pair: &str) -> Result<()> {
+        let (key, value) = pair
+            .split_once('=')
+            .with_context(|| format!("expect `key=value`, got `{pair}`"))?;
+        self.set(key.trim(), value.trim())
+    }
+
+    /// Describe the effective `config` of the chat line by line, marking the
+    /// settings of the chat.
+    pub fn describe(&self, config: &Config) -> String {
+        let Value::Object(effective) =
+            serde_json::to_value(config).expect("config is always serializable")
+        else {
+            unreachable!("config is a struct")
+        };
+        self.fields()
+            .into_iter()
+            .map(|(key, own)| {
+                let mark = if own.is_null() { "" } else { " (this chat)" };
+                format!("{key} = {}{mark}", effective[&key])
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Telegram sends every photo in several sizes, scaled from the same image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PhotoSizeSelection {
+    Smallest,
+    Largest,
+    #[default]
+    Closest,
+}
+
+impl PhotoSizeSelection {
+    /// Select the size to hash. Sizes larger than `max_file_size` are never
+    /// selected.
+    pub fn select(
+        self,
+        sizes: &[PhotoSize],
+        target_resolution: u32,
+        max_file_size: u32,
+    ) -> Option<&PhotoSize> {
+        let sizes = sizes.iter().filter(|x| x.file.size <= max_file_size);
+        let area = |x: &&PhotoSize| u64::from(x.width) * u64::from(x.height);
+        match self {
+            Self::Smallest => sizes.min_by_key(area),
+            Self::Largest => sizes.max_by_key(area),
+            Self::Closest => {
+                sizes.min_by_key(|x| x.width.max(x.height).abs_diff(target_resolution))
+            }
+        }
+    }
+}
+
+/// When a repeated sticker is a Mars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StickerMode {
+    /// a sticker posted ever before
+    Repeat,
+    /// a sticker posted `sticker_burst_count` times within
+    /// `sticker_burst_minutes`
+    #[default]
+    Burst,
+}
+
+/// A token bucket limit: `burst` tokens at most, refilled by `per_minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// A way to answer a Mars, see [`Config::response`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResponseMode {
+    Reply,
+    React,
+    Forward,
+    Delete,
+    Log,
+}
+
+impl ResponseMode {
+    const NAMES: [(&'static str, Self); 5] = [
+        ("reply", Self::Reply),
+        ("react", Self::React),
+        ("forward", Self::Forward),
+        ("delete", Self::Delete),
+        ("log", Self::Log),
+    ];
+
+    fn name(self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|x| x.1 == self)
+            .expect("every mode is named")
+            .0
+    }
+}
+
+/// The combined response modes of a Mars, written like `react,forward`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response(Vec<ResponseMode>);
+
+impl Response {
+    pub fn contains(&self, mode: ResponseMode) -> bool {
+        self.0.contains(&mode)
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self(vec![ResponseMode::Reply])
+    }
+}
+
+impl FromStr for Response {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut modes = s
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                ResponseMode::NAMES
+                    .iter()
+                    .find(|(name, _)| *name == x)
+                    .map(|x| x.1)
+                    .with_context(|| {
+                        format!(
+                            "unknown response `{x}`, expect some of reply, react, forward, \
+                             delete and log"
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if modes.is_empty() {
+            bail!("empty response, use `log` to only log a Mars");
+        }
+        modes.sort_unstable();
+        modes.dedup();
+        Ok(Self(modes))
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.0.iter().map(|x| x.name()).collect::<Vec<_>>();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl Serialize for Response {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Response {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::FileMeta;
+
+    use super::*;
+    use crate::db::MemoryDb;
+
+    fn photo(side: u32, file_size: u32) -> PhotoSize {
+        PhotoSize {
+            file: FileMeta {
+                id: format!("id{side}"),
+                unique_id: format!("unique{side}"),
+                size: file_size,
+            },
+            width: side,
+            height: side * 3 / 4,
+        }
+    }
+
+    #[test]
+    fn test_select_photo_size() {
+        let sizes = [
+            photo(90, 1_000),
+            photo(320, 10_000),
+            photo(800, 50_000),
+            photo(1280, 100_000),
+        ];
+        let select = |selection: PhotoSizeSelection, target, max| {
+            selection.select(&sizes, target, max).map(|x| x.width)
+        };
+        assert_eq!(select(PhotoSizeSelection::Smallest, 0, u32::MAX), Some(90));
+        assert_eq!(select(PhotoSizeSelection::Largest, 0, u32::MAX), Some(1280));
+        assert_eq!(select(PhotoSizeSelection::Largest, 0, 60_000), Some(800));
+        assert_eq!(
+            select(PhotoSizeSelection::Closest, 320, u32::MAX),
+            Some(320)
+        );
+        assert_eq!(
+            select(PhotoSizeSelection::Closest, 700, u32::MAX),
+            Some(800)
+        );
+        assert_eq!(select(PhotoSizeSelection::Closest, 1280, 20_000), Some(320));
+        assert_eq!(select(PhotoSizeSelection::Smallest, 0, 100), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = "forward, react,react".parse::<Response>().unwrap();
+        assert!(response.contains(ResponseMode::React));
+        assert!(response.contains(ResponseMode::Forward));
+        assert!(!response.contains(ResponseMode::Reply));
+        assert_eq!(response.to_string(), "react,forward");
+        assert_eq!(Response::default().to_string(), "reply");
+        assert!("".parse::<Response>().is_err());
+        assert!("react,shout".parse::<Response>().is_err());
+    }
+
+    #[test]
+    fn test_chat_settings() {
+        let mut settings = ChatSettings::default();
+        settings.set_pair("detect_text = true").unwrap();
+        settings.set("retention", "30d").unwrap();
+        settings.set("mars_prompt", "42").unwrap();
+        settings.set("sticker_mode", "repeat").unwrap();
+        settings.set("response", "react,log").unwrap();
+        assert!(settings.set("mod_chat", "-100123").is_err());
+        assert_eq!(settings.detect_text, Some(true));
+        assert_eq!(settings.mars_prompt.as_deref(), Some("42"));
+        assert!(settings.set("detect_text", "maybe").is_err());
+        assert!(settings.set("similarity_threshold", "64").is_err());
+        assert!(settings.set("max_file_size", "0").is_err());
+        assert!(settings.set("text_min_length", "0").is_err());
+        settings.set("similarity_threshold", "16").unwrap();
+        settings.set("similarity_threshold", "").unwrap();
+        assert!(settings.set("token", "x").is_err());
+        assert!(settings.set_pair("detect_text").is_err());
+
+        let config = Config::default().for_chat(&settings);
+        assert!(config.detect_text);
+        assert_eq!(config.retention, "30d".parse().unwrap());
+        assert_eq!(config.sticker_mode, StickerMode::Repeat);
+        assert_eq!(config.response.to_string(), "react,log");
+        assert_eq!(config.mod_chat, None);
+        assert!(settings
+            .describe(&config)
+            .contains("detect_text = true (this chat)"));
+
+        settings.set("detect_text", "").unwrap();
+        assert_eq!(settings.detect_text, None);
+        assert!(!Config::default().for_chat(&settings).detect_text);
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = Config::default();
+        config.validate().unwrap();
+        config.video_match_ratio = 1.5;
+        assert!(config.validate().is_err());
+        config.video_match_ratio = 1.0;
+        config.similarity_threshold = 64;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_migrate_chat_retention() {
+        let db = MemoryDb::default();
+        let mut settings = ChatSettings::default();
+        settings.set("retention", "1d").unwrap();
+        db.save_settings(&(-100_123).into(), &settings).unwrap();
+        let mut config = Config::default();
+        for (chat, retention) in [("-100123", "7d"), ("456", "2w")] {
+            config
+                .chat_retention
+                .insert(chat.to_owned(), retention.parse().unwrap());
         }
+        let migrated = migrate_chat_retention(&db, &config).unwrap();
+        assert_eq!(migrated, vec![ChatKey::from(456)]);
+        let retention = |chat: i64| db.query_settings(&chat.into()).unwrap().retention;
+        assert_eq!(retention(-100_123), Some("1d".parse().unwrap()));
+        assert_eq!(retention(456), Some("2w".parse().unwrap()));
+        assert_eq!(migrate_chat_retention(&db, &config).unwrap(), vec![]);
     }
 }
@@ -0,0 +1,113 @@
+//! An async facade of [`DbOperation`].
+//!
+//! Backend calls wait for locks and disk I/O, so they run on the blocking
+//! threads of tokio instead of stalling the runtime threads which handle
+//! updates.
+
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::Semaphore;
+
+use super::{DbOperation, DB};
+
+/// The most backend calls running at once. Further calls wait in a fair
+/// queue.
+const MAX_RUNNING: usize = 4;
+/// The most backend calls running or waiting. Further calls fail at once, so
+/// a stalled backend does not pile up the updates of all chats.
+const MAX_QUEUED: usize = 256;
+
+pub static ASYNC_DB: LazyLock<AsyncDb> =
+    LazyLock::new(|| AsyncDb::new(&**DB, MAX_RUNNING, MAX_QUEUED));
+
+type Db = dyn DbOperation + Send + Sync;
+
+pub struct AsyncDb {
+    db: &'static Db,
+    running: Semaphore,
+    queued: Semaphore,
+}
+
+impl AsyncDb {
+    pub const fn new(db: &'static Db, max_running: usize, max_queued: usize) -> Self {
+        Self {
+            db,
+            running: Semaphore::const_new(max_running),
+            queued: Semaphore::const_new(max_queued),
+        }
+    }
+
+    /// Run `f` with the database on a blocking thread. Fails if `max_queued`
+    /// calls are already running or waiting.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&'static Db) -> Result<T> + Send + 'static,
+    {
+        let _queued = self
+            .queued
+            .try_acquire()
+            .map_err(|_| anyhow!("too many database calls are waiting"))?;
+        let _permit = self.running.acquire().await?;
+        let db = self.db;
+        tokio::task::spawn_blocking(move || f(db)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{tests::test_dbs, MarsImage};
+
+    #[tokio::test]
+    async fn test_insert_many() {
+        for (_dir, db) in test_dbs() {
+            let db = AsyncDb::new(Box::leak(db), 1, 2);
+            let items = vec![
+                MarsImage::new(1, [1]),
+                MarsImage::new(2, [2]),
+                MarsImage::new(3, [1]),
+            ];
+            let (result, other) = tokio::join!(
+                db.run(|db| db.insert_many(&123.into(), items)),
+                db.run(|db| db.insert_many(&456.into(), vec![MarsImage::new(4, [1])]))
+            );
+            assert_eq!(
+                result.unwrap(),
+                vec![None, None, Some(MarsImage::new(1, [1]))]
+            );
+            assert_eq!(other.unwrap(), vec![None]);
+            let mut all = db
+                .run(|db| db.query_all_from_table(&123.into()))
+                .await
+                .unwrap();
+            all.sort_by_key(|x| x.id);
+            assert_eq!(all, vec![MarsImage::new(1, [1]), MarsImage::new(2, [2])]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_is_bounded() {
+        let (_dir, db) = test_dbs().remove(0);
+        let db = AsyncDb::new(Box::leak(db), 1, 2);
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        // one call runs until released, one waits, and the third fails
+        let (first, second, third) = tokio::join!(
+            db.run(move |_| {
+                receiver.recv()?;
+                Ok(1)
+            }),
+            db.run(|_| Ok(2)),
+            async {
+                let third = db.run(|_| Ok(3)).await;
+                sender.send(()).unwrap();
+                third
+            }
+        );
+        assert_eq!(first.unwrap(), 1);
+        assert_eq!(second.unwrap(), 2);
+        assert!(third.is_err());
+        assert_eq!(db.run(|_| Ok(4)).await.unwrap(), 4);
+    }
+}
@@ -0,0 +1,104 @@
+//! The key of a chat in the database.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use teloxide::types::ChatId;
+
+/// A telegram chat id, e.g. `-100123`, which names the table of a chat.
+///
+/// Only decimal integers are accepted, and they are kept in canonical form, so
+/// a key is always safe as a file name or in SQL.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChatKey(String);
+
+impl ChatKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The chat id.
+    pub fn id(&self) -> i64 {
+        self.0.parse().expect("chat key is a valid integer")
+    }
+}
+
+impl From<i64> for ChatKey {
+    fn from(id: i64) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl From<ChatId> for ChatKey {
+    fn from(id: ChatId) -> Self {
+        id.0.into()
+    }
+}
+
+impl FromStr for ChatKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse::<i64>()
+            .map(Self::from)
+            .with_context(|| format!("invalid chat id `{s}`, expect an integer like `-100123`"))
+    }
+}
+
+impl TryFrom<String> for ChatKey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ChatKey> for String {
+    fn from(key: ChatKey) -> Self {
+        key.0
+    }
+}
+
+impl AsRef<str> for ChatKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ChatKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chat_key() {
+        assert_eq!("-100123".parse::<ChatKey>().unwrap().as_str(), "-100123");
+        assert_eq!("+42".parse::<ChatKey>().unwrap().as_str(), "42");
+        assert_eq!(ChatKey::from(ChatId(-5)).id(), -5);
+        for s in [
+            "",
+            "../db",
+            "..",
+            "123/../456",
+            "/tmp",
+            "123]; DROP TABLE images; --",
+            "1' OR '1'='1",
+            "12 3",
+            "99999999999999999999",
+        ] {
+            assert!(s.parse::<ChatKey>().is_err(), "{s}");
+        }
+        assert!(serde_json::from_str::<ChatKey>(r#""../x""#).is_err());
+        assert_eq!(
+            serde_json::to_string(&ChatKey::from(-1)).unwrap(),
+            r#""-1""#
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Copy all records from one database to another, possibly of another
+//! backend.
+
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use super::{
+    memory::SNAPSHOT_FILE, new_db, open_existing, Backend, ChatKey, DbOperation, MemoryDb,
+};
+use crate::config::ChatSettings;
+
+/// A database given as `<backend>:<path>`, e.g. `sled:~/.local/mars-bot/db`.
+/// A leading `~` of the path is the home directory, as the shell does not
+/// expand it after `:`. The path of the memory backend is the dir of its
+/// snapshot, which is used whether `memory_snapshot` is set or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbSpec {
+    pub backend: Backend,
+    pub path: PathBuf,
+}
+
+impl FromStr for DbSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((backend, path)) = s.split_once(':') else {
+            bail!("expect `<backend>:<path>`, got `{s}`");
+        };
+        ensure!(!path.is_empty(), "missing database path in `{s}`");
+        let path = match path.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => home::home_dir()
+                .context("cannot find the home directory")?
+                .join(rest.trim_start_matches('/')),
+            _ => path.into(),
+        };
+        Ok(Self {
+            backend: backend.parse()?,
+            path,
+        })
+    }
+}
+
+impl DbSpec {
+    /// Open the database, the memory backend with its snapshot.
+    fn open(&self) -> Result<Box<dyn DbOperation + Send + Sync>> {
+        if self.backend != Backend::Memory {
+            return new_db(self.backend, &self.path);
+        }
+        // without a capacity, so no record is evicted while copying
+        std::fs::create_dir_all(&self.path)?;
+        Ok(Box::new(MemoryDb::new(
+            0,
+            Some(self.path.join(SNAPSHOT_FILE)),
+        )?))
+    }
+
+    /// Open the existing database to read, without migrating it.
+    fn open_source(&self) -> Result<Box<dyn DbOperation + Send + Sync>> {
+        match self.backend {
+            Backend::Memory => self.open(),
+            x => open_existing(x, &self.path),
+        }
+    }
+}
+
+/// The number of records inserted at once.
+const BATCH_SIZE: usize = 256;
+
+/// The number of records of each kind in a chat.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TableCounts {
+    pub records: usize,
+    pub unique_ids: usize,
+    pub texts: usize,
+    pub occurrences: usize,
+}
+
+impl TableCounts {
+    pub fn of<D: DbOperation + ?Sized>(db: &D, table: &ChatKey) -> Result<Self> {
+        Ok(Self {
+            records: db.query_all_from_table(table)?.len(),
+            unique_ids: db.query_all_unique_ids(table)?.len(),
+            texts: db.query_all_texts(table)?.len(),
+            occurrences: db
+                .query_all_occurrences(table)?
+                .iter()
+                .map(|x| x.1.len())
+                .sum(),
+        })
+    }
+}
+
+/// Copy every chat of the existing database `from` to the empty database
+/// `to`, and verify the counts of each chat. `from` is only read, so it must
+/// be migrated to the latest schema first. `progress` is called after each
+/// chat is copied.
+pub fn convert(
+    from: &DbSpec,
+    to: &DbSpec,
+    progress: impl FnMut(&ChatKey, TableCounts),
+) -> Result<()> {
+    ensure!(from != to, "cannot convert a database to itself");
+    ensure!(
+        from.path.exists(),
+        "the source database `{}` does not exist",
+        from.path.display()
+    );
+    let target = to.open()?;
+    copy(&*from.open_source()?, &*target, progress)?;
+    // e.g. the memory backend only writes its snapshot on close
+    target.close()
+}
+
+/// Copy every chat of the migrated `from` to the empty `to`, with its
+/// settings, and the scheduled deletions of replies.
+pub fn copy<F: DbOperation + ?Sized, T: DbOperation + ?Sized>(
+    from: &F,
+    to: &T,
+    progress: impl FnMut(&ChatKey, TableCounts),
+) -> Result<()> {
+    let pending = from.pending_migrations()?;
+    ensure!(
+        pending.is_empty(),
+        "the source database has {} pending migrations, migrate it by `mars-bot migrate` first",
+        pending.len()
+    );
+    ensure!(
+        to.list_tables()?.is_empty(),
+        "the target database is not empty"
+    );
+    copy_tables(from, to, progress).context(
+        "copy failed, the target database may be partially written, remove it before copying again",
+    )
+}
+
+fn copy_tables<F: DbOperation + ?Sized, T: DbOperation + ?Sized>(
+    from: &F,
+    to: &T,
+    mut progress: impl FnMut(&ChatKey, TableCounts),
+) -> Result<()> {
+    for table in from.list_tables()? {
+        to.create_table_if_not_exist(&table);
+        let mut items = from.query_all_from_table(&table)?.into_iter().peekable();
+        while items.peek().is_some() {
+            to.insert_many(&table, items.by_ref().take(BATCH_SIZE).collect())?;
+        }
+        for (unique_id, sha) in from.query_all_unique_ids(&table)? {
+            to.insert_unique_id(&table, &unique_id, &sha)?;
+        }
+        for (sha, record) in from.query_all_texts(&table)? {
+            to.insert_or_get_existing_text(&table, &sha, record)?;
+        }
+        for (sha, occurrences) in from.query_all_occurrences(&table)? {
+            for occurrence in occurrences {
+                to.add_occurrence(&table, &sha, occurrence)?;
+            }
+        }
+        let settings = from.query_settings(&table)?;
+        if settings != ChatSettings::default() {
+            to.save_settings(&table, &settings)?;
+        }
+        let counts = TableCounts::of(from, &table)?;
+        let copied = TableCounts::of(to, &table)?;
+        ensure!(
+            counts == copied,
+            "counts of chat {table} mismatch: {counts:?} in source, {copied:?} in target"
+        );
+        ensure!(
+            to.query_settings(&table)? == settings,
+            "settings of chat {table} mismatch"
+        );
+        progress(&table, counts);
+    }
+    for deletion in from.query_due_deletions(i64::MAX)? {
+        to.schedule_deletion(&deletion)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::db::{MarsImage, MediaKind, Occurrence, PendingDeletion, TextRecord};
+
+    #[test]
+    fn test_parse_db_spec() {
+        assert_eq!(
+            "sqlite:/tmp/a:b".parse::<DbSpec>().unwrap(),
+            DbSpec {
+                backend: Backend::Sqlite,
+                path: "/tmp/a:b".into()
+            }
+        );
+        assert!("db".parse::<DbSpec>().is_err());
+        assert!("sled:".parse::<DbSpec>().is_err());
+        assert!("redis:db".parse::<DbSpec>().is_err());
+        let home = home::home_dir().unwrap();
+        assert_eq!(
+            "sled:~/.local/mars-bot/db".parse::<DbSpec>().unwrap().path,
+            home.join(".local/mars-bot/db")
+        );
+        assert_eq!("sled:~".parse::<DbSpec>().unwrap().path, home);
+        assert_eq!(
+            "sled:~db".parse::<DbSpec>().unwrap().path,
+            PathBuf::from("~db")
+        );
+    }
+
+    #[test]
+    fn test_convert_missing_source() {
+        let dir = TempDir::new().unwrap();
+        let from = DbSpec {
+            backend: Backend::default(),
+            path: dir.path().join("missing"),
+        };
+        let to = DbSpec {
+            backend: Backend::default(),
+            path: dir.path().join("to"),
+        };
+        assert!(convert(&from, &to, |_, _| {}).is_err());
+        assert!(!from.path.exists());
+        assert!(!to.path.exists());
+    }
+
+    #[test]
+    fn test_copy() {
+        let (dir1, dir2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let from = new_db(Backend::default(), dir1.path()).unwrap();
+        let to = new_db(Backend::default(), dir2.path()).unwrap();
+        let item = MarsImage::new(1, [1])
+            .with_phash(Some(7))
+            .with_time(Some(8));
+        let video = MarsImage::new(2, [2]).with_video(MediaKind::Video, vec![1, 2], Some(3));
+        from.insert_to_table(&123.into(), item.clone()).unwrap();
+        from.insert_to_table(&123.into(), video.clone()).unwrap();
+        from.insert_unique_id(&123.into(), "AQADx", &[1]).unwrap();
+        from.insert_or_get_existing_text(&456.into(), &[3], TextRecord::new(4, Some(9)))
+            .unwrap();
+        from.add_occurrence(&456.into(), &[3], Occurrence::new(4, Some(10), Some(5)))
+            .unwrap();
+        from.add_occurrence(&456.into(), &[3], Occurrence::new(6, None, None))
+            .unwrap();
+        let mut settings = ChatSettings::default();
+        settings.set("detect_text", "true").unwrap();
+        from.save_settings(&456.into(), &settings).unwrap();
+        // a chat with only settings
+        from.save_settings(&789.into(), &settings).unwrap();
+        let deletion = PendingDeletion::new(123.into(), 5, 100);
+        from.schedule_deletion(&deletion).unwrap();
+
+        let mut copied = vec![];
+        copy(&*from, &*to, |table, counts| {
+            copied.push((table.to_owned(), counts));
+        })
+        .unwrap();
+        copied.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            copied,
+            vec![
+                (
+                    ChatKey::from(123),
+                    TableCounts {
+                        records: 2,
+                        unique_ids: 1,
+                        ..Default::default()
+                    }
+                ),
+                (
+                    ChatKey::from(456),
+                    TableCounts {
+                        texts: 1,
+                        occurrences: 2,
+                        ..Default::default()
+                    }
+                ),
+                (ChatKey::from(789), TableCounts::default()),
+            ]
+        );
+        assert_eq!(
+            to.query_by_unique_id(&123.into(), "AQADx").unwrap(),
+            Some(item)
+        );
+        assert_eq!(to.query_from_table(&123.into(), &[2]).unwrap(), Some(video));
+        assert_eq!(
+            to.insert_or_get_existing_text(&456.into(), &[3], TextRecord::new(9, None))
+                .unwrap(),
+            Some(TextRecord::new(4, Some(9)))
+        );
+        assert_eq!(to.query_settings(&456.into()).unwrap(), settings);
+        assert_eq!(to.query_settings(&789.into()).unwrap(), settings);
+        assert_eq!(to.query_due_deletions(i64::MAX).unwrap(), vec![deletion]);
+        // the target must be empty
+        assert!(copy(&*from, &*to, |_, _| {}).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_copy_unmigrated_source() {
+        let from = crate::db::Sqlite::new_memory();
+        from.inner
+            .lock()
+            .unwrap()
+            .execute_batch("UPDATE meta SET value = 2 WHERE key = 'schema_version';")
+            .unwrap();
+        let to = MemoryDb::default();
+        assert!(copy(&from, &to, |_, _| {}).is_err());
+        // the source is not migrated
+        assert_eq!(from.schema_version().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_convert_memory_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let spec = |backend, name| DbSpec {
+            backend,
+            path: dir.path().join(name),
+        };
+        let item = MarsImage::new(1, [1]).with_phash(Some(7));
+        let from = spec(Backend::default(), "from");
+        let db = from.open().unwrap();
+        db.insert_to_table(&123.into(), item.clone()).unwrap();
+        db.close().unwrap();
+        drop(db);
+        let memory = spec(Backend::Memory, "memory");
+        convert(&from, &memory, |_, _| {}).unwrap();
+        assert!(memory.path.join(SNAPSHOT_FILE).exists());
+        // and back from the snapshot
+        let to = spec(Backend::default(), "to");
+        convert(&memory, &to, |_, _| {}).unwrap();
+        assert_eq!(
+            to.open()
+                .unwrap()
+                .query_all_from_table(&123.into())
+                .unwrap(),
+            vec![item]
+        );
+    }
+
+    #[cfg(all(feature = "sled", feature = "sqlite"))]
+    #[test]
+    fn test_convert_sled_to_sqlite() {
+        let (dir1, dir2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let from = DbSpec {
+            backend: Backend::Sled,
+            path: dir1.path().into(),
+        };
+        let to = DbSpec {
+            backend: Backend::Sqlite,
+            path: dir2.path().join("mars.sqlite"),
+        };
+        let item = MarsImage::new(1, [1]).with_phash(Some(7));
+        crate::db::SledDb::new(dir1.path())
+            .insert_to_table(&123.into(), item.clone())
+            .unwrap();
+        let mut tables = vec![];
+        convert(&from, &to, |table, _| tables.push(table.to_owned())).unwrap();
+        assert_eq!(tables, vec![ChatKey::from(123)]);
+        assert_eq!(
+            crate::db::Sqlite::new(dir2.path())
+                .unwrap()
+                .query_all_from_table(&123.into())
+                .unwrap(),
+            vec![item]
+        );
+        assert!(convert(&from, &from, |_, _| {}).is_err());
+    }
+}
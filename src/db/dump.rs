@@ -0,0 +1,528 @@
+//! Export and import the records of a chat as JSON Lines or CSV, with hashes
+//! encoded in hex.
+//!
+//! Each line is one entry: an image record, a `file_unique_id`, a text, an
+//! occurrence or the settings of the chat, told apart by its `type`.
+
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    convert::TableCounts, ChatKey, DbOperation, MarsImage, MediaKind, Occurrence, TextRecord,
+};
+use crate::config::ChatSettings;
+
+/// The file format of exported records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// one JSON object per line
+    #[default]
+    Jsonl,
+    /// one row per entry, with unused columns left empty
+    Csv,
+}
+
+impl Format {
+    /// Guess the format by the extension of `path`, JSON Lines by default.
+    pub fn of_path(path: &Path) -> Self {
+        if path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("csv"))
+        {
+            Self::Csv
+        } else {
+            Self::Jsonl
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "jsonl" => Self::Jsonl,
+            "csv" => Self::Csv,
+            x => bail!("unknown format `{x}`, expect `jsonl` or `csv`"),
+        })
+    }
+}
+
+/// A record of a chat, with hashes in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Image {
+        id: i32,
+        sha: String,
+        phash: Option<String>,
+        kind: MediaKind,
+        frames: Vec<String>,
+        duration: Option<u32>,
+        time: Option<i64>,
+    },
+    UniqueId {
+        unique_id: String,
+        sha: String,
+    },
+    Text {
+        id: i32,
+        sha: String,
+        time: Option<i64>,
+    },
+    Occurrence {
+        id: i32,
+        sha: String,
+        time: Option<i64>,
+        sender: Option<i64>,
+    },
+    Settings {
+        settings: ChatSettings,
+    },
+}
+
+fn encode_hash(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+fn decode_hash(hash: &str) -> Result<u64> {
+    u64::from_str_radix(hash, 16).with_context(|| format!("invalid hash `{hash}`"))
+}
+
+fn decode_sha(sha: &str) -> Result<Vec<u8>> {
+    hex::decode(sha).with_context(|| format!("invalid sha `{sha}`"))
+}
+
+impl Entry {
+    fn of_image(item: MarsImage) -> Self {
+        Self::Image {
+            id: item.id,
+            sha: hex::encode(item.sha),
+            phash: item.phash.map(encode_hash),
+            kind: item.kind,
+            frames: item.frames.into_iter().map(encode_hash).collect(),
+            duration: item.duration,
+            time: item.time,
+        }
+    }
+
+    /// All entries of `table`.
+    pub fn all_of<D: DbOperation + ?Sized>(db: &D, table: &ChatKey) -> Result<Vec<Self>> {
+        let mut entries: Vec<_> = db
+            .query_all_from_table(table)?
+            .into_iter()
+            .map(Self::of_image)
+            .collect();
+        entries.extend(
+            db.query_all_unique_ids(table)?
+                .into_iter()
+                .map(|(unique_id, sha)| Self::UniqueId {
+                    unique_id,
+                    sha: hex::encode(sha),
+                }),
+        );
+        entries.extend(
+            db.query_all_texts(table)?
+                .into_iter()
+                .map(|(sha, record)| Self::Text {
+                    id: record.id,
+                    sha: hex::encode(sha),
+                    time: record.time,
+                }),
+        );
+        for (sha, occurrences) in db.query_all_occurrences(table)? {
+            entries.extend(occurrences.into_iter().map(|x| Self::Occurrence {
+                id: x.id,
+                sha: hex::encode(&sha),
+                time: x.time,
+                sender: x.sender,
+            }));
+        }
+        let settings = db.query_settings(table)?;
+        if settings != ChatSettings::default() {
+            entries.push(Self::Settings { settings });
+        }
+        Ok(entries)
+    }
+
+    /// Insert this entry to `table`. An existing image or text of the same sha
+    /// is kept, while settings replace the existing ones.
+    pub fn insert_to<D: DbOperation + ?Sized>(self, db: &D, table: &ChatKey) -> Result<()> {
+        match self {
+            Self::Image {
+                id,
+                sha,
+                phash,
+                kind,
+                frames,
+                duration,
+                time,
+            } => {
+                let frames = frames
+                    .iter()
+                    .map(|x| decode_hash(x))
+                    .collect::<Result<_>>()?;
+                let item = MarsImage::new(id, decode_sha(&sha)?)
+                    .with_phash(phash.as_deref().map(decode_hash).transpose()?)
+                    .with_video(kind, frames, duration)
+                    .with_time(time);
+                db.insert_or_get_existing(table, item)?;
+            }
+            Self::UniqueId { unique_id, sha } => {
+                db.insert_unique_id(table, &unique_id, &decode_sha(&sha)?)?;
+            }
+            Self::Text { id, sha, time } => {
+                db.insert_or_get_existing_text(
+                    table,
+                    &decode_sha(&sha)?,
+                    TextRecord::new(id, time),
+                )?;
+            }
+            Self::Occurrence {
+                id,
+                sha,
+                time,
+                sender,
+            } => {
+                db.add_occurrence(table, &decode_sha(&sha)?, Occurrence::new(id, time, sender))?;
+            }
+            Self::Settings { settings } => db.save_settings(table, &settings)?,
+        }
+        Ok(())
+    }
+
+    const fn count(&self, counts: &mut TableCounts) {
+        match self {
+            Self::Image { .. } => counts.records += 1,
+            Self::UniqueId { .. } => counts.unique_ids += 1,
+            Self::Text { .. } => counts.texts += 1,
+            Self::Occurrence { .. } => counts.occurrences += 1,
+            Self::Settings { .. } => {}
+        }
+    }
+}
+
+/// An [`Entry`] as a CSV row. Frames are separated by spaces, and settings
+/// are in JSON.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Row {
+    #[serde(rename = "type")]
+    ty: String,
+    id: Option<i32>,
+    sha: String,
+    phash: Option<String>,
+    kind: Option<MediaKind>,
+    frames: Option<String>,
+    duration: Option<u32>,
+    time: Option<i64>,
+    unique_id: Option<String>,
+    sender: Option<i64>,
+    settings: Option<String>,
+}
+
+impl From<Entry> for Row {
+    fn from(entry: Entry) -> Self {
+        match entry {
+            Entry::Image {
+                id,
+                sha,
+                phash,
+                kind,
+                frames,
+                duration,
+                time,
+            } => Self {
+                ty: "image".to_owned(),
+                id: Some(id),
+                sha,
+                phash,
+                kind: Some(kind),
+                frames: (!frames.is_empty()).then(|| frames.join(" ")),
+                duration,
+                time,
+                ..Default::default()
+            },
+            Entry::UniqueId { unique_id, sha } => Self {
+                ty: "unique_id".to_owned(),
+                sha,
+                unique_id: Some(unique_id),
+                ..Default::default()
+            },
+            Entry::Text { id, sha, time } => Self {
+                ty: "text".to_owned(),
+                id: Some(id),
+                sha,
+                time,
+                ..Default::default()
+            },
+            Entry::Occurrence {
+                id,
+                sha,
+                time,
+                sender,
+            } => Self {
+                ty: "occurrence".to_owned(),
+                id: Some(id),
+                sha,
+                time,
+                sender,
+                ..Default::default()
+            },
+            Entry::Settings { settings } => Self {
+                ty: "settings".to_owned(),
+                settings: Some(
+                    serde_json::to_string(&settings).expect("settings are always serializable"),
+                ),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl TryFrom<Row> for Entry {
+    type Error = anyhow::Error;
+
+    fn try_from(row: Row) -> Result<Self> {
+        let id = || row.id.context("missing id");
+        Ok(match row.ty.as_str() {
+            "image" => Self::Image {
+                id: id()?,
+                sha: row.sha,
+                phash: row.phash,
+                kind: row.kind.unwrap_or_default(),
+                frames: row
+                    .frames
+                    .iter()
+                    .flat_map(|x| x.split_whitespace())
+                    .map(ToOwned::to_owned)
+                    .collect(),
+                duration: row.duration,
+                time: row.time,
+            },
+            "unique_id" => Self::UniqueId {
+                unique_id: row.unique_id.context("missing unique_id")?,
+                sha: row.sha,
+            },
+            "text" => Self::Text {
+                id: id()?,
+                sha: row.sha,
+                time: row.time,
+            },
+            "occurrence" => Self::Occurrence {
+                id: id()?,
+                sha: row.sha,
+                time: row.time,
+                sender: row.sender,
+            },
+            "settings" => Self::Settings {
+                settings: serde_json::from_str(
+                    row.settings.as_deref().context("missing settings")?,
+                )?,
+            },
+            x => bail!("unknown entry type `{x}`"),
+        })
+    }
+}
+
+/// Write all records of `table` to `writer`.
+///
+/// # Returns
+///
+/// The number of exported records of each kind.
+pub fn export<D: DbOperation + ?Sized>(
+    db: &D,
+    table: &ChatKey,
+    format: Format,
+    mut writer: impl Write,
+) -> Result<TableCounts> {
+    let mut counts = TableCounts::default();
+    let entries = Entry::all_of(db, table)?;
+    match format {
+        Format::Jsonl => {
+            for entry in entries {
+                entry.count(&mut counts);
+                serde_json::to_writer(&mut writer, &entry)?;
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for entry in entries {
+                entry.count(&mut counts);
+                writer.serialize(Row::from(entry))?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(counts)
+}
+
+/// Insert all records read from `reader` to `table`. Existing images and texts
+/// of the same sha are kept.
+///
+/// # Returns
+///
+/// The number of imported records of each kind.
+pub fn import<D: DbOperation + ?Sized>(
+    db: &D,
+    table: &ChatKey,
+    format: Format,
+    reader: impl BufRead,
+) -> Result<TableCounts> {
+    let mut counts = TableCounts::default();
+    let result = read_entries(format, reader, |entry| {
+        entry.count(&mut counts);
+        entry.insert_to(db, table)
+    });
+    // a loaded tree of the chat misses the imported records
+    db.index().forget(table);
+    result.map(|()| counts)
+}
+
+/// Read the entries of `format`, and pass them to `insert` in order.
+fn read_entries(
+    format: Format,
+    reader: impl BufRead,
+    mut insert: impl FnMut(Entry) -> Result<()>,
+) -> Result<()> {
+    match format {
+        Format::Jsonl => {
+            for (line, text) in reader.lines().enumerate() {
+                let text = text?;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                serde_json::from_str(&text)
+                    .map_err(anyhow::Error::from)
+                    .and_then(&mut insert)
+                    .with_context(|| format!("invalid entry at line {}", line + 1))?;
+            }
+        }
+        Format::Csv => {
+            for (line, row) in csv::Reader::from_reader(reader)
+                .deserialize::<Row>()
+                .enumerate()
+            {
+                row.map_err(anyhow::Error::from)
+                    .and_then(Entry::try_from)
+                    .and_then(&mut insert)
+                    .with_context(|| format!("invalid entry at row {}", line + 1))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_dbs;
+
+    fn fill<D: DbOperation + ?Sized>(db: &D) {
+        let image = MarsImage::new(1, [0xab, 0x01])
+            .with_phash(Some(0xff))
+            .with_time(Some(100));
+        let video = MarsImage::new(2, [0xcd])
+            .with_video(MediaKind::Video, vec![1, u64::MAX], Some(3))
+            .with_time(Some(200));
+        db.insert_to_table(&123.into(), image).unwrap();
+        db.insert_to_table(&123.into(), video).unwrap();
+        db.insert_unique_id(&123.into(), "AQADx", &[0xab, 0x01])
+            .unwrap();
+        db.insert_or_get_existing_text(&123.into(), &[0xef], TextRecord::new(3, Some(300)))
+            .unwrap();
+        db.add_occurrence(&123.into(), &[0xef], Occurrence::new(3, Some(300), Some(7)))
+            .unwrap();
+        db.add_occurrence(&123.into(), &[0xef], Occurrence::new(4, None, None))
+            .unwrap();
+        let mut settings = ChatSettings::default();
+        settings.set("mars_prompt", "a, \"b\"").unwrap();
+        db.save_settings(&123.into(), &settings).unwrap();
+    }
+
+    fn sorted<D: DbOperation + ?Sized>(db: &D, table: &ChatKey) -> Vec<String> {
+        let mut entries = Entry::all_of(db, table)
+            .unwrap()
+            .into_iter()
+            .map(|x| serde_json::to_string(&x).unwrap())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn test_export_jsonl() {
+        for (_dir, db) in test_dbs() {
+            fill(&*db);
+            let mut out = vec![];
+            let counts = export(&*db, &123.into(), Format::Jsonl, &mut out).unwrap();
+            assert_eq!(
+                counts,
+                TableCounts {
+                    records: 2,
+                    unique_ids: 1,
+                    texts: 1,
+                    occurrences: 2,
+                }
+            );
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.lines().any(|x| x
+                == r#"{"type":"image","id":1,"sha":"ab01","phash":"00000000000000ff","kind":"image","frames":[],"duration":null,"time":100}"#));
+            assert!(out
+                .lines()
+                .any(|x| x == r#"{"type":"unique_id","unique_id":"AQADx","sha":"ab01"}"#));
+            assert!(out
+                .lines()
+                .any(|x| x.starts_with(r#"{"type":"settings","settings":{"#)
+                    && x.contains(r#""mars_prompt":"a, \"b\"""#)));
+        }
+    }
+
+    #[test]
+    fn test_export_import() {
+        for format in [Format::Jsonl, Format::Csv] {
+            for (_dir, db) in test_dbs() {
+                fill(&*db);
+                let mut out = vec![];
+                let exported = export(&*db, &123.into(), format, &mut out).unwrap();
+                let find = || db.index().find_nearest(&*db, &456.into(), 0xff, 0, 0, None);
+                // the tree of the chat is loaded before importing
+                assert_eq!(find().unwrap(), None);
+                let imported = import(&*db, &456.into(), format, out.as_slice()).unwrap();
+                assert_eq!(find().unwrap().map(|x| x.1.id), Some(1));
+                assert_eq!(exported, imported);
+                assert_eq!(sorted(&*db, &123.into()), sorted(&*db, &456.into()));
+                assert_eq!(
+                    db.query_settings(&456.into()).unwrap(),
+                    db.query_settings(&123.into()).unwrap()
+                );
+                // importing again keeps existing records
+                import(&*db, &456.into(), format, out.as_slice()).unwrap();
+                assert_eq!(sorted(&*db, &123.into()), sorted(&*db, &456.into()));
+            }
+        }
+        let dbs = test_dbs();
+        let db = &dbs[0].1;
+        assert!(import(
+            &**db,
+            &123.into(),
+            Format::Jsonl,
+            br#"{"type":"text"}"#.as_slice()
+        )
+        .is_err());
+        assert!(import(
+            &**db,
+            &123.into(),
+            Format::Csv,
+            b"type,sha\nimage,zz\n".as_slice()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! In-memory nearest-neighbour index of perceptual hashes.
+//!
+//! The perceptual hashes themselves are persisted by the db backends. The
+//! index is a [BK-tree](https://en.wikipedia.org/wiki/BK-tree) per chat, which
+//! is rebuilt from the backend on startup and kept in sync on every insert, so
+//! a lookup only visits a small part of a chat's images. Each db handle owns
+//! its index, see [`DbOperation::index`].
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use super::{retention::is_expired, ChatKey, DbOperation, MarsImage};
+use crate::hash::{hamming_distance, video::frame_match_ratio};
+
+#[derive(Debug, Default)]
+pub struct BkTree {
+    root: Option<Node>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node {
+    hash: u64,
+    /// all images with exactly this hash
+    items: Vec<MarsImage>,
+    /// children keyed by their distance to this node
+    children: HashMap<u32, Self>,
+}
+
+impl Node {
+    fn new(hash: u64, item: MarsImage) -> Self {
+        Self {
+            hash,
+            items: vec![item],
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl BkTree {
+    /// Insert a record with one of its perceptual hashes. A video is inserted
+    /// once per frame.
+    pub fn insert(&mut self, hash: u64, item: MarsImage) {
+        self.len += 1;
+        let Some(mut node) = self.root.as_mut() else {
+            self.root = Some(Node::new(hash, item));
+            return;
+        };
+        loop {
+            let distance = hamming_distance(node.hash, hash);
+            if distance == 0 {
+                node.items.push(item);
+                return;
+            }
+            match node.children.entry(distance) {
+                Entry::Vacant(entry) => {
+                    entry.insert(Node::new(hash, item));
+                    return;
+                }
+                Entry::Occupied(entry) => node = entry.into_mut(),
+            }
+        }
+    }
+
+    /// Remove a record inserted with `hash`. The node is kept, so the tree
+    /// stays valid.
+    pub fn remove(&mut self, hash: u64, sha: &[u8]) {
+        let mut node = self.root.as_mut();
+        while let Some(x) = node {
+            let distance = hamming_distance(x.hash, hash);
+            if distance == 0 {
+                let len = x.items.len();
+                x.items.retain(|x| x.sha != sha);
+                self.len -= len - x.items.len();
+                return;
+            }
+            node = x.children.get_mut(&distance);
+        }
+    }
+
+    /// Find all images whose hash is within `max_distance` of `hash`, with
+    /// their distance.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(u32, &MarsImage)> {
+        let mut result = vec![];
+        let mut stack = self.root.iter().collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                result.extend(node.items.iter().map(|x| (distance, x)));
+            }
+            let range = distance.saturating_sub(max_distance)..=distance + max_distance;
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(k, _)| range.contains(k))
+                    .map(|(_, v)| v),
+            );
+        }
+        result
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl FromIterator<MarsImage> for BkTree {
+    fn from_iter<T: IntoIterator<Item = MarsImage>>(iter: T) -> Self {
+        let mut tree = Self::default();
+        for item in iter {
+            for hash in item.fingerprints() {
+                tree.insert(hash, item.clone());
+            }
+        }
+        tree
+    }
+}
+
+/// The tree of a chat, `None` until it is loaded from the db backend.
+type ChatTree = Arc<Mutex<Option<BkTree>>>;
+
+/// The BK-trees of all chats of a db, loaded lazily from it. A tree is loaded
+/// under the lock of its own chat, so other chats are not blocked.
+#[derive(Debug, Default)]
+pub struct FingerprintIndex {
+    trees: Mutex<HashMap<ChatKey, ChatTree>>,
+}
+
+impl FingerprintIndex {
+    /// Rebuild the index of all tables in the db. Returns the number of
+    /// indexed images.
+    pub fn rebuild<D: DbOperation + ?Sized>(&self, db: &D) -> Result<usize> {
+        let mut trees = HashMap::new();
+        for table in db.list_tables()? {
+            let tree = db.query_all_from_table(&table)?.into_iter().collect();
+            trees.insert(table, tree);
+        }
+        let count = trees.values().map(BkTree::len).sum();
+        *self.trees.lock().unwrap() = trees
+            .into_iter()
+            .map(|(k, v)| (k, Arc::new(Mutex::new(Some(v)))))
+            .collect();
+        Ok(count)
+    }
+
+    /// Add a record which has been inserted to the db. If the tree is loaded
+    /// from the db just now, it already contains the record and is not
+    /// changed.
+    pub fn insert<D: DbOperation + ?Sized>(
+        &self,
+        db: &D,
+        table: &ChatKey,
+        item: &MarsImage,
+    ) -> Result<()> {
+        self.with_tree(db, table, |tree| {
+            for hash in item.fingerprints() {
+                if !tree.find(hash, 0).iter().any(|(_, x)| x.sha == item.sha) {
+                    tree.insert(hash, item.clone());
+                }
+            }
+        })
+    }
+
+    /// Remove records which are evicted from the db. A tree which is not
+    /// loaded yet is not loaded for it.
+    pub fn remove(&self, table: &ChatKey, items: &[MarsImage]) {
+        let Some(chat) = self.trees.lock().unwrap().get(table).cloned() else {
+            return;
+        };
+        if let Some(tree) = chat.lock().unwrap().as_mut() {
+            for item in items {
+                for hash in item.fingerprints() {
+                    tree.remove(hash, &item.sha);
+                }
+            }
+        };
+    }
+
+    /// Drop the tree of a table, so it is loaded from the db again on next
+    /// use. Called after records are removed from the db.
+    pub fn forget(&self, table: &ChatKey) {
+        self.trees.lock().unwrap().remove(table);
+    }
+
+    /// Find the closest image within `max_distance`. If there are several
+    /// ones, the earliest message wins. Records of message `exclude_id`,
+    /// records expired at `cutoff` and videos are skipped.
+    pub fn find_nearest<D: DbOperation + ?Sized>(
+        &self,
+        db: &D,
+        table: &ChatKey,
+        hash: u64,
+        max_distance: u32,
+        exclude_id: i32,
+        cutoff: Option<i64>,
+    ) -> Result<Option<(u32, MarsImage)>> {
+        self.with_tree(db, table, |tree| {
+            tree.find(hash, max_distance)
+                .into_iter()
+                .filter(|(_, x)| {
+                    x.id != exclude_id && !x.kind.is_video() && !is_expired(x.time, cutoff)
+                })
+                .min_by_key(|(distance, x)| (*distance, x.id))
+                .map(|(distance, x)| (distance, x.clone()))
+        })
+    }
+
+    /// Find the video with the most frames similar to `item`, if at least
+    /// `min_ratio` of the frames of `item` match. Videos whose duration
+    /// differs by more than one second, or expired at `cutoff`, are skipped.
+    pub fn find_similar_video<D: DbOperation + ?Sized>(
+        &self,
+        db: &D,
+        table: &ChatKey,
+        item: &MarsImage,
+        max_distance: u32,
+        min_ratio: f32,
+        cutoff: Option<i64>,
+    ) -> Result<Option<(f32, MarsImage)>> {
+        self.with_tree(db, table, |tree| {
+            let mut candidates = HashMap::new();
+            for &frame in &item.frames {
+                for (_, x) in tree.find(frame, max_distance) {
+                    let duration_matches = match (x.duration, item.duration) {
+                        (Some(a), Some(b)) => a.abs_diff(b) <= 1,
+                        _ => true,
+                    };
+                    if x.id != item.id
+                        && x.kind.is_video()
+                        && duration_matches
+                        && !is_expired(x.time, cutoff)
+                    {
+                        candidates.entry(&x.sha).or_insert(x);
+                    }
+                }
+            }
+            candidates
+                .into_values()
+                .map(|x| (frame_match_ratio(&item.frames, &x.frames, max_distance), x))
+                .filter(|(ratio, _)| *ratio >= min_ratio)
+                .max_by(|(r1, x1), (r2, x2)| r1.total_cmp(r2).then(x2.id.cmp(&x1.id)))
+                .map(|(ratio, x)| (ratio, x.clone()))
+        })
+    }
+
+    fn with_tree<D: DbOperation + ?Sized, T>(
+        &self,
+        db: &D,
+        table: &ChatKey,
+        f: impl FnOnce(&mut BkTree) -> T,
+    ) -> Result<T> {
+        let chat = self
+            .trees
+            .lock()
+            .unwrap()
+            .entry(table.clone())
+            .or_default()
+            .clone();
+        let mut tree = chat.lock().unwrap();
+        if tree.is_none() {
+            *tree = Some(db.query_all_from_table(table)?.into_iter().collect());
+        }
+        Ok(f(tree.as_mut().expect("loaded above")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::db::{new_db, Backend, MediaKind};
+
+    /// xorshift, to get deterministic pseudo random hashes
+    fn random_hashes(count: usize) -> Vec<u64> {
+        let mut x = 0x2545_f491_4f6c_dd1d_u64;
+        (0..count)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bk_tree_equals_linear_scan() {
+        let hashes = random_hashes(2000);
+        let items = hashes
+            .iter()
+            .zip(0..)
+            .map(|(&hash, id)| MarsImage::new(id, id.to_le_bytes()).with_phash(Some(hash)))
+            .collect::<Vec<_>>();
+        let tree = items.iter().cloned().collect::<BkTree>();
+        assert_eq!(tree.len(), 2000);
+        for (i, &target) in hashes.iter().enumerate().step_by(97) {
+            // flip some bits, so the target is not always in the tree
+            let target = target ^ (0b1011 << (i % 60));
+            for max_distance in [0, 3, 10, 24] {
+                let mut expected = items
+                    .iter()
+                    .filter(|x| hamming_distance(x.phash.unwrap(), target) <= max_distance)
+                    .map(|x| x.id)
+                    .collect::<Vec<_>>();
+                let mut found = tree
+                    .find(target, max_distance)
+                    .into_iter()
+                    .map(|(_, x)| x.id)
+                    .collect::<Vec<_>>();
+                expected.sort_unstable();
+                found.sort_unstable();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bk_tree_same_hash_and_no_hash() {
+        let tree = [
+            MarsImage::new(1, [1]).with_phash(Some(42)),
+            MarsImage::new(2, [2]).with_phash(Some(42)),
+            MarsImage::new(3, [3]),
+        ]
+        .into_iter()
+        .collect::<BkTree>();
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.find(42, 0).len(), 2);
+        assert_eq!(tree.find(!42, 10).len(), 0);
+    }
+
+    #[test]
+    fn test_bk_tree_remove() {
+        let items = random_hashes(100)
+            .into_iter()
+            .zip(0..)
+            .map(|(hash, id)| MarsImage::new(id, id.to_le_bytes()).with_phash(Some(hash)))
+            .collect::<Vec<_>>();
+        let mut tree = items.iter().cloned().collect::<BkTree>();
+        for item in items.iter().step_by(2) {
+            tree.remove(item.phash.unwrap(), &item.sha);
+        }
+        assert_eq!(tree.len(), 50);
+        for item in &items {
+            let found = tree.find(item.phash.unwrap(), 0);
+            assert_eq!(found.len(), usize::from(item.id % 2 == 1));
+        }
+    }
+
+    #[test]
+    fn test_find_similar_video() {
+        let tempdir = TempDir::new().unwrap();
+        let db = new_db(Backend::default(), tempdir.path()).unwrap();
+        let index = FingerprintIndex::default();
+        let video = MarsImage::new(1, [1])
+            .with_video(
+                MediaKind::Video,
+                vec![0, 0xff, 0xff00, 0x00ff_0000],
+                Some(10),
+            )
+            .with_time(Some(100));
+        db.insert_to_table(&1.into(), video.clone()).unwrap();
+        index.insert(&*db, &1.into(), &video).unwrap();
+        let image = MarsImage::new(3, [3]).with_phash(Some(0));
+        db.insert_to_table(&1.into(), image.clone()).unwrap();
+        index.insert(&*db, &1.into(), &image).unwrap();
+
+        // 3 of 4 frames are similar
+        let repost = MarsImage::new(2, [2]).with_video(
+            MediaKind::Animation,
+            vec![1, 0xff, 0xff01, u64::MAX],
+            Some(11),
+        );
+        let found = index
+            .find_similar_video(&*db, &1.into(), &repost, 2, 0.75, Some(100))
+            .unwrap();
+        assert_eq!(found, Some((0.75, video)));
+        // expired
+        let found = index
+            .find_similar_video(&*db, &1.into(), &repost, 2, 0.75, Some(101))
+            .unwrap();
+        assert_eq!(found, None);
+        let found = index
+            .find_similar_video(&*db, &1.into(), &repost, 2, 0.8, None)
+            .unwrap();
+        assert_eq!(found, None);
+        let other = repost.with_video(MediaKind::Video, vec![1, 0xff, 0xff01], Some(20));
+        let found = index
+            .find_similar_video(&*db, &1.into(), &other, 2, 0.5, None)
+            .unwrap();
+        assert_eq!(found, None);
+        // videos are never reported as similar images
+        let found = index.find_nearest(&*db, &1.into(), 0, 0, 0, None).unwrap();
+        assert_eq!(found, Some((0, image)));
+    }
+}
@@ -0,0 +1,535 @@
+//! The in-memory backend. Nothing is persisted, unless a snapshot file is
+//! given, which is loaded on startup and saved on shutdown. Settings of chats
+//! and scheduled deletions are saved beside it as json.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    index::FingerprintIndex, retention::is_expired, ChatKey, DbOperation, MarsImage, Migration,
+    Occurrence, PendingDeletion, TextRecord,
+};
+use crate::config::ChatSettings;
+
+/// Values keyed by sha, which forget the least recently used one beyond a
+/// capacity.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "V: Serialize + for<'a> Deserialize<'a>")]
+struct Lru<V> {
+    /// the last used tick of each value
+    values: HashMap<Vec<u8>, (u64, V)>,
+    /// the key of each last used tick
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl<V> Default for Lru<V> {
+    fn default() -> Self {
+        Self {
+            values: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+}
+
+impl<V> Lru<V> {
+    /// Get a value, and mark it as the most recently used one.
+    fn get(&mut self, key: &[u8]) -> Option<&V> {
+        self.tick += 1;
+        let (tick, value) = self.values.get_mut(key)?;
+        self.order.remove(tick);
+        self.order.insert(self.tick, key.to_vec());
+        *tick = self.tick;
+        Some(value)
+    }
+
+    /// Insert a value, returns the evicted values with their keys if there
+    /// are more than `capacity` values. `0` means no limit.
+    fn insert(&mut self, key: Vec<u8>, value: V, capacity: usize) -> Vec<(Vec<u8>, V)> {
+        self.tick += 1;
+        if let Some((tick, _)) = self.values.insert(key.clone(), (self.tick, value)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key);
+        let mut evicted = vec![];
+        while capacity > 0 && self.values.len() > capacity {
+            let (_, key) = self.order.pop_first().expect("order has the same size");
+            let (_, value) = self.values.remove(&key).expect("order has the same keys");
+            evicted.push((key, value));
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let (tick, value) = self.values.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+
+    /// All values, from the least recently used one.
+    fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &V)> {
+        self.order.values().map(|x| (x, &self.values[x].1))
+    }
+}
+
+/// All records of a chat.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Chat {
+    records: Lru<MarsImage>,
+    unique_ids: HashMap<String, Vec<u8>>,
+    texts: Lru<TextRecord>,
+    occurrences: HashMap<Vec<u8>, Vec<Occurrence>>,
+}
+
+impl Chat {
+    /// Forget everything about evicted fingerprints.
+    fn forget<V>(&mut self, evicted: &[(Vec<u8>, V)]) {
+        if evicted.is_empty() {
+            return;
+        }
+        self.unique_ids
+            .retain(|_, sha| !evicted.iter().any(|(x, _)| x == sha));
+        for (sha, _) in evicted {
+            self.occurrences.remove(sha);
+        }
+    }
+
+    /// Insert an image, returns the evicted images.
+    fn insert(&mut self, item: MarsImage, capacity: usize) -> Vec<MarsImage> {
+        let evicted = self.records.insert(item.sha.clone(), item, capacity);
+        self.forget(&evicted);
+        evicted.into_iter().map(|(_, x)| x).collect()
+    }
+}
+
+/// The file name of the snapshot in the database dir.
+pub const SNAPSHOT_FILE: &str = "memory.bin";
+
+#[derive(Debug, Default)]
+pub struct MemoryDb {
+    chats: Mutex<HashMap<ChatKey, Chat>>,
+    settings: Mutex<HashMap<ChatKey, ChatSettings>>,
+    deletions: Mutex<Vec<PendingDeletion>>,
+    /// the most images and texts kept in each chat, `0` means no limit
+    capacity: usize,
+    /// the file to load on startup and save on shutdown
+    snapshot: Option<PathBuf>,
+    index: FingerprintIndex,
+}
+
+impl MemoryDb {
+    /// Create a database keeping at most `capacity` images and texts in each
+    /// chat. If `snapshot` exists, it is loaded.
+    pub fn new(capacity: usize, snapshot: Option<PathBuf>) -> Result<Self> {
+        let chats = match &snapshot {
+            Some(path) if path.exists() => {
+                bincode::serde::decode_from_slice(&std::fs::read(path)?, bincode::config::legacy())?
+                    .0
+            }
+            _ => HashMap::new(),
+        };
+        let settings = load_json(snapshot.as_deref().map(settings_path))?;
+        let deletions = load_json(snapshot.as_deref().map(deletions_path))?;
+        Ok(Self {
+            chats: Mutex::new(chats),
+            settings: Mutex::new(settings),
+            deletions: Mutex::new(deletions),
+            capacity,
+            snapshot,
+            index: FingerprintIndex::default(),
+        })
+    }
+
+    fn with_chat<T>(&self, table: &ChatKey, f: impl FnOnce(&mut Chat) -> T) -> T {
+        f(self.chats.lock().unwrap().entry(table.clone()).or_default())
+    }
+
+    /// Run `f` on a chat if it exists, otherwise returns the default value.
+    fn with_existing_chat<T: Default>(&self, table: &ChatKey, f: impl FnOnce(&mut Chat) -> T) -> T {
+        self.chats
+            .lock()
+            .unwrap()
+            .get_mut(table)
+            .map_or_else(T::default, f)
+    }
+}
+
+impl DbOperation for MemoryDb {
+    fn create_table_if_not_exist(&self, table: &ChatKey) {
+        self.with_chat(table, |_| ());
+    }
+
+    fn query_from_table(&self, table: &ChatKey, key: &[u8]) -> Result<Option<MarsImage>> {
+        Ok(self.with_existing_chat(table, |chat| chat.records.get(key).cloned()))
+    }
+
+    fn insert_to_table(&self, table: &ChatKey, item: MarsImage) -> Result<()> {
+        self.with_chat(table, |chat| chat.insert(item, self.capacity));
+        Ok(())
+    }
+
+    fn query_all_from_table(&self, table: &ChatKey) -> Result<Vec<MarsImage>> {
+        Ok(self.with_existing_chat(table, |chat| {
+            chat.records.iter().map(|x| x.1.clone()).collect()
+        }))
+    }
+
+    fn exist_table(&self, table: &ChatKey) -> Result<bool> {
+        Ok(self.chats.lock().unwrap().contains_key(table))
+    }
+
+    /// Chats with records or settings.
+    fn list_tables(&self) -> Result<Vec<ChatKey>> {
+        let mut tables = self
+            .chats
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for table in self.settings.lock().unwrap().keys() {
+            if !tables.contains(table) {
+                tables.push(table.clone());
+            }
+        }
+        Ok(tables)
+    }
+
+    fn insert_or_get_existing(
+        &self,
+        table: &ChatKey,
+        item: MarsImage,
+    ) -> Result<Option<MarsImage>> {
+        Ok(self.insert_or_get_existing_evicting(table, item)?.0)
+    }
+
+    fn insert_or_get_existing_evicting(
+        &self,
+        table: &ChatKey,
+        item: MarsImage,
+    ) -> Result<(Option<MarsImage>, Vec<MarsImage>)> {
+        Ok(self.with_chat(table, |chat| {
+            if let Some(existing) = chat.records.get(&item.sha) {
+                return (Some(existing.clone()), vec![]);
+            }
+            (None, chat.insert(item, self.capacity))
+        }))
+    }
+
+    fn query_by_unique_id(&self, table: &ChatKey, unique_id: &str) -> Result<Option<MarsImage>> {
+        Ok(self.with_existing_chat(table, |chat| {
+            let sha = chat.unique_ids.get(unique_id)?.clone();
+            chat.records.get(&sha).cloned()
+        }))
+    }
+
+    fn insert_unique_id(&self, table: &ChatKey, unique_id: &str, sha: &[u8]) -> Result<()> {
+        self.with_chat(table, |chat| {
+            chat.unique_ids.insert(unique_id.to_owned(), sha.to_vec());
+        });
+        Ok(())
+    }
+
+    fn query_all_unique_ids(&self, table: &ChatKey) -> Result<Vec<(String, Vec<u8>)>> {
+        Ok(self.with_existing_chat(table, |chat| {
+            chat.unique_ids
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }))
+    }
+
+    fn insert_or_get_existing_text(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        record: TextRecord,
+    ) -> Result<Option<TextRecord>> {
+        Ok(self.with_chat(table, |chat| {
+            if let Some(&existing) = chat.texts.get(sha) {
+                return Some(existing);
+            }
+            let evicted = chat.texts.insert(sha.to_vec(), record, self.capacity);
+            chat.forget(&evicted);
+            None
+        }))
+    }
+
+    fn query_all_texts(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, TextRecord)>> {
+        Ok(self.with_existing_chat(table, |chat| {
+            chat.texts.iter().map(|(k, &v)| (k.clone(), v)).collect()
+        }))
+    }
+
+    fn add_occurrence(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        occurrence: Occurrence,
+    ) -> Result<Vec<Occurrence>> {
+        Ok(self.with_chat(table, |chat| {
+            let occurrences = chat.occurrences.entry(sha.to_vec()).or_default();
+            if let Err(index) = occurrences.binary_search_by_key(&occurrence.id, |x| x.id) {
+                occurrences.insert(index, occurrence);
+            }
+            occurrences.clone()
+        }))
+    }
+
+    fn query_all_occurrences(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, Vec<Occurrence>)>> {
+        Ok(self.with_existing_chat(table, |chat| {
+            chat.occurrences
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }))
+    }
+
+    fn drop_table(&self, table: &ChatKey) -> Result<()> {
+        self.chats.lock().unwrap().remove(table);
+        self.settings.lock().unwrap().remove(table);
+        Ok(())
+    }
+
+    fn purge_expired(&self, table: &ChatKey, before: i64) -> Result<usize> {
+        let before = Some(before);
+        Ok(self.with_existing_chat(table, |chat| {
+            let records = chat
+                .records
+                .iter()
+                .filter(|(_, x)| is_expired(x.time, before))
+                .map(|(sha, _)| sha.clone())
+                .collect::<Vec<_>>();
+            let texts = chat
+                .texts
+                .iter()
+                .filter(|(_, x)| is_expired(x.time, before))
+                .map(|(sha, _)| sha.clone())
+                .collect::<Vec<_>>();
+            for sha in &records {
+                chat.records.remove(sha);
+            }
+            for sha in &texts {
+                chat.texts.remove(sha);
+            }
+            chat.unique_ids.retain(|_, sha| !records.contains(sha));
+            for occurrences in chat.occurrences.values_mut() {
+                occurrences.retain(|x| !is_expired(x.time, before));
+            }
+            chat.occurrences.retain(|_, x| !x.is_empty());
+            records.len() + texts.len()
+        }))
+    }
+
+    fn remove_by_key(&self, table: &ChatKey, sha: &[u8]) -> Result<usize> {
+        Ok(self.with_existing_chat(table, |chat| {
+            let removed = usize::from(chat.records.remove(sha).is_some())
+                + usize::from(chat.texts.remove(sha).is_some());
+            chat.forget(&[(sha.to_vec(), ())]);
+            removed
+        }))
+    }
+
+    fn compact_table(&self, table: &ChatKey) -> Result<usize> {
+        Ok(self.with_existing_chat(table, |chat| {
+            let mut seen = std::collections::HashSet::new();
+            let duplicated = chat
+                .records
+                .iter()
+                .filter(|(_, item)| !seen.insert(item.id))
+                .map(|(sha, _)| sha.clone())
+                .collect::<Vec<_>>();
+            for sha in &duplicated {
+                chat.records.remove(sha);
+            }
+            duplicated.len()
+        }))
+    }
+
+    fn query_settings(&self, table: &ChatKey) -> Result<ChatSettings> {
+        Ok(self
+            .settings
+            .lock()
+            .unwrap()
+            .get(table)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn save_settings(&self, table: &ChatKey, settings: &ChatSettings) -> Result<()> {
+        self.settings
+            .lock()
+            .unwrap()
+            .insert(table.clone(), settings.clone());
+        Ok(())
+    }
+
+    fn schedule_deletion(&self, deletion: &PendingDeletion) -> Result<()> {
+        self.remove_deletion(deletion)?;
+        self.deletions.lock().unwrap().push(deletion.clone());
+        Ok(())
+    }
+
+    fn query_due_deletions(&self, now: i64) -> Result<Vec<PendingDeletion>> {
+        let mut due = self
+            .deletions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| x.due <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        due.sort_by_key(|x| x.due);
+        Ok(due)
+    }
+
+    fn remove_deletion(&self, deletion: &PendingDeletion) -> Result<()> {
+        self.deletions
+            .lock()
+            .unwrap()
+            .retain(|x| (&x.chat, x.message_id) != (&deletion.chat, deletion.message_id));
+        Ok(())
+    }
+
+    /// There is nothing to migrate in memory. Snapshots are loaded as is.
+    fn migrations(&self) -> &'static [Migration] {
+        &[]
+    }
+
+    fn schema_version(&self) -> Result<u32> {
+        Ok(0)
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        bail!("unknown memory migration: {}", migration.version)
+    }
+
+    fn index(&self) -> &FingerprintIndex {
+        &self.index
+    }
+
+    fn close(&self) -> Result<()> {
+        let Some(path) = &self.snapshot else {
+            return Ok(());
+        };
+        let bytes =
+            bincode::serde::encode_to_vec(&*self.chats.lock().unwrap(), bincode::config::legacy())?;
+        write_atomically(path, &bytes)?;
+        write_atomically(
+            &settings_path(path),
+            &serde_json::to_vec(&*self.settings.lock().unwrap())?,
+        )?;
+        write_atomically(
+            &deletions_path(path),
+            &serde_json::to_vec(&*self.deletions.lock().unwrap())?,
+        )
+    }
+}
+
+/// The file of settings beside the snapshot at `path`.
+fn settings_path(path: &Path) -> PathBuf {
+    path.with_extension("settings.json")
+}
+
+/// The file of scheduled deletions beside the snapshot at `path`.
+fn deletions_path(path: &Path) -> PathBuf {
+    path.with_extension("deletions.json")
+}
+
+/// Load a json file if it exists, otherwise the default value.
+fn load_json<T: DeserializeOwned + Default>(path: Option<PathBuf>) -> Result<T> {
+    Ok(match path {
+        Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+        _ => T::default(),
+    })
+}
+
+/// Write a file, and do not leave a broken one if writing is interrupted.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, bytes)?;
+    std::fs::rename(temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let db = MemoryDb::new(2, None).unwrap();
+        db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+            .unwrap();
+        db.insert_to_table(&123.into(), MarsImage::new(2, [2]))
+            .unwrap();
+        db.insert_unique_id(&123.into(), "AQADx", &[1]).unwrap();
+        // 1 is used, so 2 is the least recently used one
+        assert!(db.query_from_table(&123.into(), &[1]).unwrap().is_some());
+        db.insert_to_table(&123.into(), MarsImage::new(3, [3]))
+            .unwrap();
+        let mut ids = db
+            .query_all_from_table(&123.into())
+            .unwrap()
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 3]);
+        db.insert_to_table(&123.into(), MarsImage::new(4, [4]))
+            .unwrap();
+        assert_eq!(db.query_from_table(&123.into(), &[1]).unwrap(), None);
+        assert_eq!(db.query_all_unique_ids(&123.into()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_eviction_is_returned() {
+        let db = MemoryDb::new(1, None).unwrap();
+        let old = MarsImage::new(1, [1]).with_phash(Some(7));
+        let new = MarsImage::new(2, [2]).with_phash(Some(!7));
+        let result = db
+            .insert_or_get_existing_evicting(&123.into(), old.clone())
+            .unwrap();
+        assert_eq!(result, (None, vec![]));
+        let result = db
+            .insert_or_get_existing_evicting(&123.into(), new.clone())
+            .unwrap();
+        assert_eq!(result, (None, vec![old]));
+        let result = db.insert_or_get_existing_evicting(&123.into(), new.clone());
+        assert_eq!(result.unwrap(), (Some(new), vec![]));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let tempdir = TempDir::new().unwrap();
+        let path = tempdir.path().join("memory.bin");
+        let db = MemoryDb::new(0, Some(path.clone())).unwrap();
+        let item = MarsImage::new(1, [1]).with_phash(Some(7));
+        db.insert_to_table(&123.into(), item.clone()).unwrap();
+        db.insert_or_get_existing_text(&123.into(), &[2], TextRecord::new(2, Some(3)))
+            .unwrap();
+        let mut settings = ChatSettings::default();
+        settings.set("detect_text", "false").unwrap();
+        db.save_settings(&123.into(), &settings).unwrap();
+        let deletion = PendingDeletion::new(123.into(), 5, 100);
+        db.schedule_deletion(&deletion).unwrap();
+        db.close().unwrap();
+        let db = MemoryDb::new(0, Some(path)).unwrap();
+        assert_eq!(db.query_settings(&123.into()).unwrap(), settings);
+        assert_eq!(db.query_due_deletions(100).unwrap(), vec![deletion]);
+        assert_eq!(db.query_all_from_table(&123.into()).unwrap(), vec![item]);
+        assert_eq!(
+            db.query_all_texts(&123.into()).unwrap(),
+            vec![(vec![2], TextRecord::new(2, Some(3)))]
+        );
+    }
+}
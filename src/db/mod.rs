@@ -1,48 +1,402 @@
+pub mod async_db;
+pub mod chat_key;
+pub mod convert;
+pub mod dump;
+pub mod index;
+pub mod memory;
+pub mod retention;
 #[cfg(feature = "sled")]
 pub mod sled;
 #[cfg(feature = "sled")]
 pub use sled::*;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
-use std::{path::Path, sync::LazyLock};
+use std::{path::Path, str::FromStr, sync::LazyLock};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+pub use async_db::ASYNC_DB;
+pub use chat_key::ChatKey;
+use die_exit::DieWith;
+pub use memory::MemoryDb;
+use serde::{Deserialize, Serialize};
 #[cfg(feature = "sqlite")]
 pub use sqlite::*;
 
+use self::index::FingerprintIndex;
 pub use crate::utils::db_path;
+use crate::{
+    config::{ChatSettings, CONFIG},
+    utils::OnceLockDefaultInit,
+};
 
-#[cfg(feature = "sqlite")]
-pub static DB: LazyLock<Box<dyn DbOperation<Connection = ()> + Send + Sync>> =
-    LazyLock::new(|| new_db(db_path()));
+pub static DB: LazyLock<Box<dyn DbOperation + Send + Sync>> = LazyLock::new(|| {
+    let config = CONFIG.get_or_init_default();
+    new_db(config.backend, &config.db_dir).die_with(|e| format!("Cannot attach db backend: {e:?}"))
+});
 
-#[cfg(feature = "sled")]
-pub static DB: LazyLock<Box<dyn DbOperation<Connection = sled_crate::Db> + Send + Sync>> =
-    LazyLock::new(|| new_db(db_path()));
+/// The database backend. Only backends of enabled cargo features can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Sled,
+    Sqlite,
+    /// Nothing is persisted, unless `memory_snapshot` is set.
+    Memory,
+}
+
+impl Default for Backend {
+    /// sled if it is compiled in, otherwise sqlite, otherwise memory.
+    fn default() -> Self {
+        if cfg!(feature = "sled") {
+            Self::Sled
+        } else if cfg!(feature = "sqlite") {
+            Self::Sqlite
+        } else {
+            Self::Memory
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "sled" => Self::Sled,
+            "sqlite" => Self::Sqlite,
+            "memory" => Self::Memory,
+            x => bail!("unknown backend `{x}`, expect `sled`, `sqlite` or `memory`"),
+        })
+    }
+}
+
+/// Open the database of `backend` at `path`. The memory backend saves its
+/// snapshot to `path` if `memory_snapshot` is set.
+pub fn new_db(
+    backend: Backend,
+    path: impl AsRef<Path>,
+) -> Result<Box<dyn DbOperation + Send + Sync>> {
+    let path = path.as_ref();
+    Ok(match backend {
+        #[cfg(feature = "sled")]
+        Backend::Sled => Box::new(SledDb::new(path)),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Box::new(Sqlite::new(path)?),
+        Backend::Memory => {
+            let config = CONFIG.get_or_init_default();
+            let snapshot = config
+                .memory_snapshot
+                .then(|| std::fs::create_dir_all(path).map(|()| path.join(memory::SNAPSHOT_FILE)));
+            Box::new(MemoryDb::new(
+                config.memory_capacity,
+                snapshot.transpose()?,
+            )?)
+        }
+        #[allow(unreachable_patterns)]
+        x => bail!("backend `{x:?}` is not compiled in"),
+    })
+}
+
+/// Open the existing database of `backend` at `path` to read, without
+/// creating or migrating anything. The memory backend is empty.
+#[cfg_attr(
+    not(any(feature = "sled", feature = "sqlite")),
+    allow(unused_variables)
+)]
+pub fn open_existing(
+    backend: Backend,
+    path: impl AsRef<Path>,
+) -> Result<Box<dyn DbOperation + Send + Sync>> {
+    Ok(match backend {
+        #[cfg(feature = "sled")]
+        Backend::Sled => Box::new(SledDb::open_existing(path.as_ref())?),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Box::new(Sqlite::open_read_only(path)?),
+        Backend::Memory => Box::new(MemoryDb::default()),
+        #[allow(unreachable_patterns)]
+        x => bail!("backend `{x:?}` is not compiled in"),
+    })
+}
+
+/// The schema version and the pending migrations of the existing database of
+/// `backend` at `path`, read without creating or changing anything. Snapshots
+/// of the memory backend are loaded as is, so they have nothing to migrate.
+pub fn inspect_migrations(
+    backend: Backend,
+    path: impl AsRef<Path>,
+) -> Result<(u32, Vec<&'static Migration>)> {
+    let db = open_existing(backend, path)?;
+    Ok((db.schema_version()?, db.pending_migrations()?))
+}
 
 #[allow(unused)]
 pub trait DbOperation {
-    type Connection;
-    fn create_table_if_not_exist(&self, table: &str) -> Self::Connection;
-    fn query_from_table(&self, table: &str, key: &[u8]) -> Result<Option<MarsImage>>;
-    fn insert_to_table(&self, table: &str, item: MarsImage) -> Result<()>;
-    fn exist_table(&self, table: &str) -> Result<bool>;
+    fn create_table_if_not_exist(&self, table: &ChatKey);
+    fn query_from_table(&self, table: &ChatKey, key: &[u8]) -> Result<Option<MarsImage>>;
+    fn insert_to_table(&self, table: &ChatKey, item: MarsImage) -> Result<()>;
+    /// Get all items in a table, returns an empty vec if the table does not
+    /// exist.
+    fn query_all_from_table(&self, table: &ChatKey) -> Result<Vec<MarsImage>>;
+    fn exist_table(&self, table: &ChatKey) -> Result<bool>;
+    /// Get the names of all tables.
+    fn list_tables(&self) -> Result<Vec<ChatKey>>;
     /// Try to insert an item to table
     ///
     /// # Returns
     ///
     /// - If the item already exists, do not insert and return the existing one.
     /// - If the item is inserted successfully, return `None`.
-    fn insert_or_get_existing(&self, table: &str, item: MarsImage) -> Result<Option<MarsImage>>;
-    fn drop_table(&self, table: &str) -> Result<()>;
+    fn insert_or_get_existing(&self, table: &ChatKey, item: MarsImage)
+        -> Result<Option<MarsImage>>;
+    /// [`Self::insert_or_get_existing`], and the records evicted to make room
+    /// for `item`, which the caller removes from the index. Only the memory
+    /// backend evicts records.
+    fn insert_or_get_existing_evicting(
+        &self,
+        table: &ChatKey,
+        item: MarsImage,
+    ) -> Result<(Option<MarsImage>, Vec<MarsImage>)> {
+        Ok((self.insert_or_get_existing(table, item)?, vec![]))
+    }
+    /// [`Self::insert_or_get_existing`] each item in order. Backends may
+    /// write the batch at once.
+    fn insert_many(
+        &self,
+        table: &ChatKey,
+        items: Vec<MarsImage>,
+    ) -> Result<Vec<Option<MarsImage>>> {
+        items
+            .into_iter()
+            .map(|x| self.insert_or_get_existing(table, x))
+            .collect()
+    }
+    /// Find the image recorded for a telegram `file_unique_id`.
+    fn query_by_unique_id(&self, table: &ChatKey, unique_id: &str) -> Result<Option<MarsImage>>;
+    /// Record the telegram `file_unique_id` of an inserted image, so the same
+    /// file can be found without downloading it.
+    fn insert_unique_id(&self, table: &ChatKey, unique_id: &str, sha: &[u8]) -> Result<()>;
+    /// Get all `(file_unique_id, sha)` pairs of a chat.
+    fn query_all_unique_ids(&self, table: &ChatKey) -> Result<Vec<(String, Vec<u8>)>>;
+    /// Record the first message of a text or link hash, in a table apart from
+    /// the images.
+    ///
+    /// # Returns
+    ///
+    /// - If the hash already exists, do not insert and return the existing
+    ///   record.
+    /// - If the hash is inserted successfully, return `None`.
+    fn insert_or_get_existing_text(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        record: TextRecord,
+    ) -> Result<Option<TextRecord>>;
+    /// Get all `(sha, first message)` pairs of texts and links of a chat.
+    fn query_all_texts(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, TextRecord)>>;
+    /// Append an occurrence to the list of a fingerprint (the sha of an image
+    /// or of a text key), unless the message is already in it.
+    ///
+    /// # Returns
+    ///
+    /// All occurrences of the fingerprint, sorted by message id.
+    fn add_occurrence(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        occurrence: Occurrence,
+    ) -> Result<Vec<Occurrence>>;
+    /// Get all fingerprints of a chat with their occurrences.
+    fn query_all_occurrences(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, Vec<Occurrence>)>>;
+    fn drop_table(&self, table: &ChatKey) -> Result<()>;
+    /// Remove the images, texts and occurrences of a chat inserted before the
+    /// unix timestamp `before`, and the `file_unique_id`s of removed images.
+    /// Records without a time are kept.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed images and texts.
+    fn purge_expired(&self, table: &ChatKey, before: i64) -> Result<usize>;
+    /// Remove the image or text of fingerprint `sha`, with its
+    /// `file_unique_id`s and occurrences.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed images and texts.
+    fn remove_by_key(&self, table: &ChatKey, sha: &[u8]) -> Result<usize>;
+    /// Older versions recorded every size of a photo, so one message may have
+    /// several records. Remove all but one record of each message.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed records.
+    fn compact_table(&self, table: &ChatKey) -> Result<usize>;
+    /// The settings of a chat, default if they are never saved.
+    fn query_settings(&self, table: &ChatKey) -> Result<ChatSettings>;
+    /// Replace the settings of a chat. They are removed with the chat by
+    /// [`Self::drop_table`].
+    fn save_settings(&self, table: &ChatKey, settings: &ChatSettings) -> Result<()>;
+    /// Schedule deleting a message of the bot, replacing the schedule of the
+    /// same message. Schedules are kept when the chat is dropped.
+    fn schedule_deletion(&self, deletion: &PendingDeletion) -> Result<()>;
+    /// The scheduled deletions of all chats due at `now`, the earliest first.
+    fn query_due_deletions(&self, now: i64) -> Result<Vec<PendingDeletion>>;
+    /// Remove a scheduled deletion, after it is done or given up.
+    fn remove_deletion(&self, deletion: &PendingDeletion) -> Result<()>;
+    /// All migrations of this backend, sorted by version.
+    fn migrations(&self) -> &'static [Migration];
+    /// The schema version of the database. Databases created before schema
+    /// versioning are version `0`.
+    fn schema_version(&self) -> Result<u32>;
+    /// Upgrade the database in place to the version of `migration`.
+    fn apply_migration(&self, migration: &Migration) -> Result<()>;
+    /// The perceptual hash index of the records of this database. It is kept
+    /// in sync by the callers which insert or remove records.
+    fn index(&self) -> &FingerprintIndex;
+    /// Called on shutdown.
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+    /// The migrations which are not applied yet.
+    fn pending_migrations(&self) -> Result<Vec<&'static Migration>> {
+        let version = self.schema_version()?;
+        Ok(self
+            .migrations()
+            .iter()
+            .filter(|x| x.version > version)
+            .collect())
+    }
+    /// Apply all pending migrations in order.
+    ///
+    /// # Returns
+    ///
+    /// The applied migrations.
+    fn migrate(&self) -> Result<Vec<&'static Migration>> {
+        let pending = self.pending_migrations()?;
+        for migration in &pending {
+            self.apply_migration(migration)?;
+        }
+        Ok(pending)
+    }
+}
+
+/// A step to upgrade the database schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// the schema version after this step
+    pub version: u32,
+    pub description: &'static str,
+}
+
+impl std::fmt::Display for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}: {}", self.version, self.description)
+    }
+}
+
+/// The kind of media a record is hashed from.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    /// a photo or an image document
+    #[default]
+    Image = 0,
+    Video = 1,
+    /// a GIF, or a soundless mp4 converted from it by telegram
+    Animation = 2,
+    /// a round video message
+    VideoNote = 3,
+    /// a sticker or custom emojis, hashed from its key instead of the file
+    Sticker = 4,
+}
+
+impl MediaKind {
+    /// Records of video kinds are compared by their frames, and can match each
+    /// other.
+    pub const fn is_video(self) -> bool {
+        matches!(self, Self::Video | Self::Animation | Self::VideoNote)
+    }
+
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub const fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Image,
+            1 => Self::Video,
+            2 => Self::Animation,
+            3 => Self::VideoNote,
+            4 => Self::Sticker,
+            _ => return None,
+        })
+    }
+}
+
+/// A message posting a fingerprint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Occurrence {
+    /// the message id in a group
+    pub id: i32,
+    /// the unix timestamp of the message, unknown for records from older
+    /// versions
+    pub time: Option<i64>,
+    /// the user or channel id of the sender
+    pub sender: Option<i64>,
+}
+
+impl Occurrence {
+    pub const fn new(id: i32, time: Option<i64>, sender: Option<i64>) -> Self {
+        Self { id, time, sender }
+    }
+}
+
+/// The first message of a text or link hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextRecord {
+    /// the message id in a group
+    pub id: i32,
+    /// the unix timestamp of the message, unknown for records from older
+    /// versions
+    pub time: Option<i64>,
+}
+
+impl TextRecord {
+    pub const fn new(id: i32, time: Option<i64>) -> Self {
+        Self { id, time }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A message of the bot to delete at `due`, a unix timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingDeletion {
+    pub chat: ChatKey,
+    pub message_id: i32,
+    pub due: i64,
+}
+
+impl PendingDeletion {
+    pub const fn new(chat: ChatKey, message_id: i32, due: i64) -> Self {
+        Self {
+            chat,
+            message_id,
+            due,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MarsImage {
     /// the message id in a group
     pub id: i32,
     /// the sha-256 for image
     pub sha: Vec<u8>,
+    /// the perceptual hash for image
+    pub phash: Option<u64>,
+    pub kind: MediaKind,
+    /// the perceptual hashes of some frames, for video kinds
+    pub frames: Vec<u64>,
+    /// the duration in seconds, for video kinds
+    pub duration: Option<u32>,
+    /// the unix timestamp of the message, which the record expires from
+    pub time: Option<i64>,
 }
 
 impl MarsImage {
@@ -50,62 +404,352 @@ impl MarsImage {
         Self {
             id,
             sha: sha.into(),
+            phash: None,
+            kind: MediaKind::Image,
+            frames: vec![],
+            duration: None,
+            time: None,
         }
     }
-}
 
-#[cfg(feature = "sqlite")]
-fn new_db(path: impl AsRef<Path>) -> Box<dyn DbOperation<Connection = ()> + Send + Sync> {
-    Box::new(Sqlite::new(path.as_ref()).die_with(|e| format!("Cannot attach db backend:{e:?}")))
-}
+    #[must_use]
+    pub const fn with_time(mut self, time: Option<i64>) -> Self {
+        self.time = time;
+        self
+    }
 
-#[cfg(feature = "sled")]
-fn new_db(
-    path: impl AsRef<Path>,
-) -> Box<dyn DbOperation<Connection = sled_crate::Db> + Send + Sync> {
-    Box::new(SledDb::new(path.as_ref()))
+    #[must_use]
+    pub const fn with_phash(mut self, phash: Option<u64>) -> Self {
+        self.phash = phash;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_kind(mut self, kind: MediaKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    #[must_use]
+    pub fn with_video(mut self, kind: MediaKind, frames: Vec<u64>, duration: Option<u32>) -> Self {
+        self.kind = kind;
+        self.frames = frames;
+        self.duration = duration;
+        self
+    }
+
+    /// All perceptual hashes of this record: the image hash and frame hashes.
+    pub fn fingerprints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.phash.into_iter().chain(self.frames.iter().copied())
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use tempfile::TempDir;
 
     use super::*;
 
+    /// A new database of each compiled-in backend, with its directory.
+    pub fn test_dbs() -> Vec<(TempDir, Box<dyn DbOperation + Send + Sync>)> {
+        [Backend::Sled, Backend::Sqlite, Backend::Memory]
+            .into_iter()
+            .filter_map(|backend| {
+                let tempdir = TempDir::new().unwrap();
+                let db = new_db(backend, tempdir.path()).ok()?;
+                Some((tempdir, db))
+            })
+            .collect()
+    }
+
     #[test]
     fn test_create_table_and_drop_table() {
-        let tempdir = TempDir::new().unwrap();
-        let db = new_db(tempdir);
-        db.create_table_if_not_exist("123456789");
-        assert!(db.exist_table("123456789").unwrap());
-        db.drop_table("123456789").unwrap();
-        assert!(!db.exist_table("123456789").unwrap());
+        for (_dir, db) in test_dbs() {
+            db.create_table_if_not_exist(&123_456_789.into());
+            assert!(db.exist_table(&123_456_789.into()).unwrap());
+            db.drop_table(&123_456_789.into()).unwrap();
+            assert!(!db.exist_table(&123_456_789.into()).unwrap());
+            // a chat which never recorded anything
+            db.drop_table(&987_654_321.into()).unwrap();
+        }
     }
 
     #[test]
     fn test_insert_get() {
-        let tempdir = TempDir::new().unwrap();
-        let db = new_db(tempdir);
-        db.create_table_if_not_exist("123456789");
-        let item = MarsImage::new(123_456, [1, 2, 3, 4, 5, 6]);
-        db.insert_to_table("123456789", item.clone()).unwrap();
-        let result = db
-            .query_from_table("123456789", &[1, 2, 3, 4, 5, 6])
-            .unwrap()
-            .unwrap();
-        assert_eq!(result, item);
+        for (_dir, db) in test_dbs() {
+            db.create_table_if_not_exist(&123_456_789.into());
+            let item = MarsImage::new(123_456, [1, 2, 3, 4, 5, 6]);
+            db.insert_to_table(&123_456_789.into(), item.clone())
+                .unwrap();
+            let result = db
+                .query_from_table(&123_456_789.into(), &[1, 2, 3, 4, 5, 6])
+                .unwrap()
+                .unwrap();
+            assert_eq!(result, item);
+        }
     }
 
     #[test]
     fn test_insert_or_get_existing() {
-        let tempdir = TempDir::new().unwrap();
-        let db = new_db(tempdir);
-        db.create_table_if_not_exist("123456789");
-        let item = MarsImage::new(123_456, [1, 2, 3, 4, 5, 6]);
-        let result = db.insert_or_get_existing("123456789", item).unwrap();
-        assert!(result.is_none());
-        let item2 = MarsImage::new(654_321, [1, 2, 3, 4, 5, 6]);
-        let result = db.insert_or_get_existing("123456789", item2).unwrap();
-        assert!(result.is_some());
+        for (_dir, db) in test_dbs() {
+            db.create_table_if_not_exist(&123_456_789.into());
+            let item = MarsImage::new(123_456, [1, 2, 3, 4, 5, 6]);
+            let result = db
+                .insert_or_get_existing(&123_456_789.into(), item)
+                .unwrap();
+            assert!(result.is_none());
+            let item2 = MarsImage::new(654_321, [1, 2, 3, 4, 5, 6]);
+            let result = db
+                .insert_or_get_existing(&123_456_789.into(), item2)
+                .unwrap();
+            assert!(result.is_some());
+        }
+    }
+
+    #[test]
+    fn test_phash_and_query_all() {
+        for (_dir, db) in test_dbs() {
+            assert_eq!(
+                db.query_all_from_table(&123_456_789.into()).unwrap(),
+                vec![]
+            );
+            let item = MarsImage::new(1, [1, 2, 3]).with_phash(Some(u64::MAX));
+            let item2 = MarsImage::new(2, [4, 5, 6]);
+            db.insert_to_table(&123_456_789.into(), item.clone())
+                .unwrap();
+            db.insert_to_table(&123_456_789.into(), item2.clone())
+                .unwrap();
+            let mut all = db.query_all_from_table(&123_456_789.into()).unwrap();
+            all.sort_by_key(|x| x.id);
+            assert_eq!(all, vec![item, item2]);
+        }
+    }
+
+    #[test]
+    fn test_unique_id() {
+        for (_dir, db) in test_dbs() {
+            assert_eq!(db.query_by_unique_id(&123.into(), "AQADx").unwrap(), None);
+            let item = MarsImage::new(1, [1, 2, 3]).with_phash(Some(7));
+            db.insert_to_table(&123.into(), item.clone()).unwrap();
+            db.insert_unique_id(&123.into(), "AQADx", &item.sha)
+                .unwrap();
+            assert_eq!(
+                db.query_by_unique_id(&123.into(), "AQADx").unwrap(),
+                Some(item)
+            );
+            assert_eq!(db.query_by_unique_id(&123.into(), "AQADy").unwrap(), None);
+            assert_eq!(db.query_by_unique_id(&456.into(), "AQADx").unwrap(), None);
+            assert_eq!(db.list_tables().unwrap(), vec![ChatKey::from(123)]);
+            db.drop_table(&123.into()).unwrap();
+            assert_eq!(db.query_by_unique_id(&123.into(), "AQADx").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_new_db_is_up_to_date() {
+        for (_dir, db) in test_dbs() {
+            db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+                .unwrap();
+            assert_eq!(db.pending_migrations().unwrap(), Vec::<&Migration>::new());
+            assert_eq!(
+                db.schema_version().unwrap(),
+                db.migrations().last().map_or(0, |x| x.version)
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_occurrence() {
+        for (_dir, db) in test_dbs() {
+            let first = Occurrence::new(1, Some(100), Some(7));
+            let second = Occurrence::new(5, Some(200), None);
+            assert_eq!(
+                db.add_occurrence(&123.into(), &[1], second).unwrap(),
+                vec![second]
+            );
+            assert_eq!(
+                db.add_occurrence(&123.into(), &[1], first).unwrap(),
+                vec![first, second]
+            );
+            // a message is only recorded once
+            assert_eq!(
+                db.add_occurrence(&123.into(), &[1], first).unwrap(),
+                vec![first, second]
+            );
+            assert_eq!(
+                db.add_occurrence(&123.into(), &[2], first).unwrap(),
+                vec![first]
+            );
+            assert_eq!(
+                db.add_occurrence(&456.into(), &[1], second).unwrap(),
+                vec![second]
+            );
+            db.drop_table(&123.into()).unwrap();
+            assert_eq!(
+                db.add_occurrence(&123.into(), &[1], second).unwrap(),
+                vec![second]
+            );
+        }
+    }
+
+    #[test]
+    fn test_insert_or_get_existing_text() {
+        for (_dir, db) in test_dbs() {
+            let first = TextRecord::new(1, Some(100));
+            assert_eq!(
+                db.insert_or_get_existing_text(&123.into(), &[1, 2], first)
+                    .unwrap(),
+                None
+            );
+            assert_eq!(
+                db.insert_or_get_existing_text(&123.into(), &[1, 2], TextRecord::new(2, None))
+                    .unwrap(),
+                Some(first)
+            );
+            assert_eq!(
+                db.insert_or_get_existing_text(&456.into(), &[1, 2], TextRecord::new(3, None))
+                    .unwrap(),
+                None
+            );
+            // texts are not images
+            assert_eq!(db.query_all_from_table(&123.into()).unwrap(), vec![]);
+            db.drop_table(&123.into()).unwrap();
+            assert_eq!(
+                db.insert_or_get_existing_text(&123.into(), &[1, 2], TextRecord::new(4, None))
+                    .unwrap(),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_compact_table() {
+        for (_dir, db) in test_dbs() {
+            for sha in 0..4 {
+                db.insert_to_table(&123.into(), MarsImage::new(1, [sha]))
+                    .unwrap();
+            }
+            db.insert_to_table(&123.into(), MarsImage::new(2, [9]))
+                .unwrap();
+            assert_eq!(db.compact_table(&123.into()).unwrap(), 3);
+            assert_eq!(db.compact_table(&123.into()).unwrap(), 0);
+            let mut ids = db
+                .query_all_from_table(&123.into())
+                .unwrap()
+                .into_iter()
+                .map(|x| x.id)
+                .collect::<Vec<_>>();
+            ids.sort_unstable();
+            assert_eq!(ids, vec![1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_remove_by_key() {
+        for (_dir, db) in test_dbs() {
+            assert_eq!(db.remove_by_key(&123.into(), &[1]).unwrap(), 0);
+            db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+                .unwrap();
+            db.insert_to_table(&123.into(), MarsImage::new(2, [2]))
+                .unwrap();
+            db.insert_unique_id(&123.into(), "AQADx", &[1]).unwrap();
+            db.add_occurrence(&123.into(), &[1], Occurrence::new(1, None, None))
+                .unwrap();
+            db.insert_or_get_existing_text(&123.into(), &[3], TextRecord::new(3, None))
+                .unwrap();
+            db.insert_to_table(&456.into(), MarsImage::new(1, [1]))
+                .unwrap();
+
+            assert_eq!(db.remove_by_key(&123.into(), &[1]).unwrap(), 1);
+            assert_eq!(db.remove_by_key(&123.into(), &[3]).unwrap(), 1);
+            assert_eq!(
+                db.query_all_from_table(&123.into()).unwrap(),
+                vec![MarsImage::new(2, [2])]
+            );
+            assert_eq!(db.query_all_unique_ids(&123.into()).unwrap(), vec![]);
+            assert_eq!(db.query_all_occurrences(&123.into()).unwrap(), vec![]);
+            assert_eq!(db.query_all_texts(&123.into()).unwrap(), vec![]);
+            assert!(db.query_from_table(&456.into(), &[1]).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_settings() {
+        for (_dir, db) in test_dbs() {
+            assert_eq!(
+                db.query_settings(&123.into()).unwrap(),
+                ChatSettings::default()
+            );
+            let mut settings = ChatSettings::default();
+            settings.set("detect_video", "false").unwrap();
+            db.save_settings(&123.into(), &settings).unwrap();
+            assert_eq!(db.query_settings(&123.into()).unwrap(), settings);
+            assert_eq!(
+                db.query_settings(&456.into()).unwrap(),
+                ChatSettings::default()
+            );
+            db.drop_table(&123.into()).unwrap();
+            assert_eq!(
+                db.query_settings(&123.into()).unwrap(),
+                ChatSettings::default()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pending_deletions() {
+        for (_dir, db) in test_dbs() {
+            let first = PendingDeletion::new(123.into(), 1, 100);
+            let second = PendingDeletion::new(456.into(), 2, 50);
+            db.schedule_deletion(&first).unwrap();
+            db.schedule_deletion(&second).unwrap();
+            db.schedule_deletion(&PendingDeletion::new(123.into(), 3, 200))
+                .unwrap();
+            assert_eq!(db.query_due_deletions(10).unwrap(), vec![]);
+            assert_eq!(
+                db.query_due_deletions(100).unwrap(),
+                vec![second, first.clone()]
+            );
+            // rescheduled, and kept when the chat is dropped
+            let later = PendingDeletion::new(456.into(), 2, 150);
+            db.schedule_deletion(&later).unwrap();
+            db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+                .unwrap();
+            db.drop_table(&123.into()).unwrap();
+            assert_eq!(
+                db.query_due_deletions(150).unwrap(),
+                vec![first.clone(), later.clone()]
+            );
+            db.remove_deletion(&first).unwrap();
+            assert_eq!(db.query_due_deletions(150).unwrap(), vec![later]);
+        }
+    }
+
+    #[test]
+    fn test_video_record() {
+        for (_dir, db) in test_dbs() {
+            let item = MarsImage::new(1, [1, 2, 3])
+                .with_video(MediaKind::VideoNote, vec![1, u64::MAX, 3], Some(42))
+                .with_time(Some(1_700_000_000));
+            db.insert_to_table(&123.into(), item.clone()).unwrap();
+            assert_eq!(
+                db.query_from_table(&123.into(), &item.sha).unwrap(),
+                Some(item)
+            );
+        }
+    }
+
+    #[test]
+    fn test_list_tables() {
+        for (_dir, db) in test_dbs() {
+            assert_eq!(db.list_tables().unwrap(), Vec::<ChatKey>::new());
+            db.insert_to_table(&ChatKey::from(-100_123), MarsImage::new(1, [1]))
+                .unwrap();
+            db.insert_to_table(&456.into(), MarsImage::new(1, [1]))
+                .unwrap();
+            let mut tables = db.list_tables().unwrap();
+            tables.sort();
+            assert_eq!(tables, vec![ChatKey::from(-100_123), ChatKey::from(456)]);
+        }
     }
 }
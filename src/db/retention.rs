@@ -0,0 +1,277 @@
+//! Expiry of records older than the `retention` of their chat.
+//!
+//! Every record stores the unix timestamp of its message. Records inserted
+//! before `now - retention` are ignored on lookup, and purged by [`prune`],
+//! which runs periodically in the bot and on demand by `mars-bot prune`.
+
+use std::{fmt, str::FromStr, time::SystemTime};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{ChatKey, DbOperation, MarsImage, TextRecord};
+use crate::config::chat_config;
+
+/// How long records are kept, written as a number followed by a unit: `s`,
+/// `m`, `h`, `d` or `w`, e.g. `30d`. `never` (or `0`) keeps them forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retention {
+    /// `0` means forever
+    pub seconds: u64,
+}
+
+const UNITS: [(char, u64); 5] = [
+    ('w', 7 * 24 * 3600),
+    ('d', 24 * 3600),
+    ('h', 3600),
+    ('m', 60),
+    ('s', 1),
+];
+
+impl Retention {
+    /// Records inserted before the returned timestamp are expired, `None` if
+    /// they never expire.
+    pub fn cutoff(self, now: i64) -> Option<i64> {
+        (self.seconds > 0).then(|| now.saturating_sub_unsigned(self.seconds))
+    }
+}
+
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "never" || s == "0" {
+            return Ok(Self::default());
+        }
+        let Some(unit) = s.chars().last() else {
+            bail!("empty retention");
+        };
+        let Some(&(_, scale)) = UNITS.iter().find(|x| x.0 == unit) else {
+            bail!("unknown unit of retention `{s}`, expect one of `s`, `m`, `h`, `d` or `w`");
+        };
+        let count: u64 = s[..s.len() - unit.len_utf8()]
+            .parse()
+            .with_context(|| format!("invalid retention `{s}`"))?;
+        Ok(Self {
+            seconds: count
+                .checked_mul(scale)
+                .with_context(|| format!("retention `{s}` is too long"))?,
+        })
+    }
+}
+
+impl fmt::Display for Retention {
+    /// Written in the largest unit which divides it exactly.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.seconds == 0 {
+            return write!(f, "never");
+        }
+        let (unit, scale) = UNITS
+            .iter()
+            .find(|x| self.seconds.is_multiple_of(x.1))
+            .expect("every number is divided by 1");
+        write!(f, "{}{unit}", self.seconds / scale)
+    }
+}
+
+impl Serialize for Retention {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Retention {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The current unix timestamp.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs().cast_signed())
+}
+
+/// Records of `chat` inserted before the returned timestamp are expired,
+/// according to the config and the settings of the chat.
+pub fn cutoff<D: DbOperation + ?Sized>(db: &D, chat: &ChatKey, now: i64) -> Result<Option<i64>> {
+    Ok(chat_config(db, chat)?.retention.cutoff(now))
+}
+
+/// Whether a record inserted at `time` is expired. Records without a time
+/// never expire.
+pub const fn is_expired(time: Option<i64>, cutoff: Option<i64>) -> bool {
+    matches!((time, cutoff), (Some(time), Some(cutoff)) if time < cutoff)
+}
+
+/// Purge the records of `table` inserted before `before`, and forget them in
+/// the perceptual hash index.
+///
+/// # Returns
+///
+/// The number of purged images and texts.
+pub fn purge<D: DbOperation + ?Sized>(db: &D, table: &ChatKey, before: i64) -> Result<usize> {
+    let purged = db.purge_expired(table, before)?;
+    if purged > 0 {
+        db.index().forget(table);
+    }
+    Ok(purged)
+}
+
+/// Purge expired records of all chats.
+///
+/// # Returns
+///
+/// The chats with records purged, and the number of purged records.
+pub fn prune<D: DbOperation + ?Sized>(db: &D, now: i64) -> Result<Vec<(ChatKey, usize)>> {
+    let mut result = vec![];
+    for table in db.list_tables()? {
+        let Some(before) = cutoff(db, &table, now)? else {
+            continue;
+        };
+        let purged = purge(db, &table, before)?;
+        if purged > 0 {
+            result.push((table, purged));
+        }
+    }
+    Ok(result)
+}
+
+/// [`DbOperation::insert_or_get_existing_evicting`], but an expired existing
+/// record is removed and replaced by `item`. The removed record is returned
+/// with the evicted ones. Other expired records are left to [`prune`].
+pub fn insert_or_get_existing<D: DbOperation + ?Sized>(
+    db: &D,
+    table: &ChatKey,
+    item: MarsImage,
+    cutoff: Option<i64>,
+) -> Result<(Option<MarsImage>, Vec<MarsImage>)> {
+    match db.insert_or_get_existing_evicting(table, item.clone())? {
+        (Some(existing), _) if is_expired(existing.time, cutoff) => {
+            db.remove_by_key(table, &existing.sha)?;
+            let (found, mut evicted) = db.insert_or_get_existing_evicting(table, item)?;
+            evicted.push(existing);
+            Ok((found, evicted))
+        }
+        x => Ok(x),
+    }
+}
+
+/// [`DbOperation::insert_or_get_existing_text`], but an expired existing
+/// record is removed and replaced by `record`.
+pub fn insert_or_get_existing_text<D: DbOperation + ?Sized>(
+    db: &D,
+    table: &ChatKey,
+    sha: &[u8],
+    record: TextRecord,
+    cutoff: Option<i64>,
+) -> Result<Option<TextRecord>> {
+    match db.insert_or_get_existing_text(table, sha, record)? {
+        Some(existing) if is_expired(existing.time, cutoff) => {
+            db.remove_by_key(table, sha)?;
+            db.insert_or_get_existing_text(table, sha, record)
+        }
+        x => Ok(x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{tests::test_dbs, Occurrence};
+
+    #[test]
+    fn test_parse_retention() {
+        let days = |x: u64| Retention {
+            seconds: x * 24 * 3600,
+        };
+        assert_eq!("30d".parse::<Retention>().unwrap(), days(30));
+        assert_eq!("2w".parse::<Retention>().unwrap(), days(14));
+        assert_eq!("90m".parse::<Retention>().unwrap().seconds, 5400);
+        assert_eq!("never".parse::<Retention>().unwrap(), Retention::default());
+        assert_eq!("0".parse::<Retention>().unwrap(), Retention::default());
+        for s in ["", "d", "30", "30y", "-1d", "99999999999999999w"] {
+            assert!(s.parse::<Retention>().is_err(), "{s}");
+        }
+        assert_eq!(days(14).to_string(), "2w");
+        assert_eq!(Retention { seconds: 5400 }.to_string(), "90m");
+        assert_eq!(Retention::default().to_string(), "never");
+        assert_eq!(Retention { seconds: 10 }.cutoff(100), Some(90));
+        assert_eq!(Retention::default().cutoff(100), None);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        for (_dir, db) in test_dbs() {
+            let old = MarsImage::new(1, [1]).with_time(Some(100));
+            let new = MarsImage::new(2, [2]).with_time(Some(200));
+            let legacy = MarsImage::new(3, [3]);
+            for item in [&old, &new, &legacy] {
+                db.insert_to_table(&123.into(), item.clone()).unwrap();
+            }
+            db.insert_unique_id(&123.into(), "old", &old.sha).unwrap();
+            db.insert_unique_id(&123.into(), "new", &new.sha).unwrap();
+            db.insert_or_get_existing_text(&123.into(), &[4], TextRecord::new(4, Some(100)))
+                .unwrap();
+            db.add_occurrence(&123.into(), &[1], Occurrence::new(1, Some(100), None))
+                .unwrap();
+            db.add_occurrence(&123.into(), &[2], Occurrence::new(2, Some(200), None))
+                .unwrap();
+
+            assert_eq!(purge(&*db, &123.into(), 150).unwrap(), 2);
+            let mut all = db.query_all_from_table(&123.into()).unwrap();
+            all.sort_by_key(|x| x.id);
+            assert_eq!(all, vec![new.clone(), legacy]);
+            assert_eq!(
+                db.query_all_unique_ids(&123.into()).unwrap(),
+                vec![("new".to_owned(), new.sha)]
+            );
+            assert_eq!(db.query_all_texts(&123.into()).unwrap(), vec![]);
+            assert_eq!(
+                db.query_all_occurrences(&123.into()).unwrap(),
+                vec![(vec![2], vec![Occurrence::new(2, Some(200), None)])]
+            );
+            assert_eq!(purge(&*db, &123.into(), 150).unwrap(), 0);
+            assert_eq!(purge(&*db, &456.into(), 150).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_expired_is_replaced() {
+        for (_dir, db) in test_dbs() {
+            let old = MarsImage::new(1, [1]).with_time(Some(100));
+            let repost = MarsImage::new(2, [1]).with_time(Some(300));
+            let other = MarsImage::new(3, [3]).with_time(Some(100));
+            db.insert_to_table(&123.into(), old.clone()).unwrap();
+            db.insert_to_table(&123.into(), other.clone()).unwrap();
+            let found =
+                insert_or_get_existing(&*db, &123.into(), repost.clone(), Some(50)).unwrap();
+            assert_eq!(found, (Some(old.clone()), vec![]));
+            let found =
+                insert_or_get_existing(&*db, &123.into(), repost.clone(), Some(150)).unwrap();
+            assert_eq!(found, (None, vec![old]));
+            assert_eq!(
+                db.query_from_table(&123.into(), &[1]).unwrap(),
+                Some(repost)
+            );
+            // other expired records are left to `prune`
+            assert_eq!(db.query_from_table(&123.into(), &[3]).unwrap(), Some(other));
+
+            let text = TextRecord::new(1, Some(100));
+            let repost = TextRecord::new(2, Some(300));
+            db.insert_or_get_existing_text(&123.into(), &[2], text)
+                .unwrap();
+            let found =
+                insert_or_get_existing_text(&*db, &123.into(), &[2], repost, Some(150)).unwrap();
+            assert_eq!(found, None);
+            assert_eq!(
+                db.query_all_texts(&123.into()).unwrap(),
+                vec![(vec![2], repost)]
+            );
+        }
+    }
+}
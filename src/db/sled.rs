@@ -1,100 +1,743 @@
-use std::{path::PathBuf, sync::Mutex};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 use die_exit::DieWith;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 use sled_crate::Db;
-use uluru::LRUCache;
 
-use super::{DbOperation, MarsImage};
-use crate::utils::{FromVecU8, IntoVecU8};
+use super::{
+    index::FingerprintIndex,
+    retention::{is_expired, now},
+    ChatKey, DbOperation, MarsImage, MediaKind, Migration, Occurrence, PendingDeletion, TextRecord,
+};
+use crate::{
+    config::ChatSettings,
+    utils::{FromVecU8, IntoVecU8},
+};
+
+/// The tree mapping telegram `file_unique_id` to the sha of an image.
+const UNIQUE_ID_TREE: &str = "file_unique_ids";
+/// The tree mapping the hash of a text or link to its first message id,
+/// followed by its time if it is known.
+const TEXT_TREE: &str = "texts";
+/// The tree mapping a fingerprint to the bincode of its occurrences.
+const OCCURRENCE_TREE: &str = "occurrences";
+/// The tree holding the schema version of a chat table.
+const META_TREE: &str = "meta";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// The key of the settings of a chat in its meta tree, as json.
+const SETTINGS_KEY: &str = "settings";
+/// The db of scheduled deletions of all chats, keyed by `<chat>/<message id>`
+/// with json values. It is not named by a chat id, so it is not a chat table.
+const DELETION_DB: &str = "deletions";
+/// The most dbs of chats kept open when no call uses them.
+const MAX_OPEN_TABLES: usize = 50;
+
+/// Every chat table has its own schema version, as tables are separated dbs.
+const MIGRATIONS: [Migration; 2] = [
+    Migration {
+        version: 1,
+        description: "re-encode values of older versions: a message id, followed by a perceptual hash if it exists",
+    },
+    Migration {
+        version: 2,
+        description: "store the time of records, records of older versions are stamped with the migration time",
+    },
+];
 
 #[cfg(feature = "sled")]
 #[derive(Debug)]
 pub struct SledDb {
     pub path: PathBuf,
-    pub connection: Mutex<LRUCache<(String, Db), 50>>,
+    /// The open dbs of chats, the most recently used last. sled cannot open a
+    /// db twice, so a db still used by a call is never closed.
+    connection: Mutex<Vec<(ChatKey, Arc<Db>)>>,
+    /// opened on first use, so inspecting the migrations creates nothing
+    deletions: Mutex<Option<Db>>,
+    index: FingerprintIndex,
 }
 
 impl SledDb {
     pub fn new(path: impl Into<PathBuf>) -> Self {
         let path = path.into();
         std::fs::create_dir_all(&path).die_with(|e| format!("create database dir failed: {e:?}"));
+        Self::with_path(path)
+    }
+
+    /// Open an existing database dir without creating anything in it.
+    pub fn open_existing(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        ensure!(
+            path.is_dir(),
+            "database dir `{}` does not exist",
+            path.display()
+        );
+        Ok(Self::with_path(path))
+    }
+
+    fn with_path(path: PathBuf) -> Self {
         Self {
             path,
-            connection: Mutex::new(LRUCache::new()),
+            connection: Mutex::new(vec![]),
+            deletions: Mutex::new(None),
+            index: FingerprintIndex::default(),
         }
     }
 
-    /// create a db and insert to cache
-    #[inline]
-    pub fn connect(&self, table: &str) {
-        let conn = sled_crate::open(self.path.join(table))
-            .die_with(|e| format!("open sled db failed: {e:?}"));
-        self.connection
-            .lock()
-            .unwrap()
-            .insert((table.to_owned(), conn));
+    /// The db of scheduled deletions, opened on first use.
+    fn deletions(&self) -> Result<Db> {
+        let mut deletions = self.deletions.lock().unwrap();
+        if deletions.is_none() {
+            *deletions = Some(sled_crate::open(self.path.join(DELETION_DB))?);
+        }
+        let db = deletions.clone().expect("opened above");
+        drop(deletions);
+        Ok(db)
     }
 
-    #[inline]
-    pub fn get_table(&self, table: &str) -> Option<Db> {
-        self.connection
-            .lock()
-            .unwrap()
-            .find(|x| x.0 == table)
-            .map(|x| x.1.clone())
+    /// Get the db of a table from cache, or open it. A new db is at the
+    /// latest schema version. It is opened under the lock of the cache, so it
+    /// is never opened twice.
+    pub fn open_table(&self, table: &ChatKey) -> Result<Arc<Db>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(index) = connection.iter().position(|x| x.0 == *table) {
+            let entry = connection.remove(index);
+            let db = entry.1.clone();
+            connection.push(entry);
+            return Ok(db);
+        }
+        let path = self.path.join(table.as_str());
+        let new = !path.exists();
+        let db = Arc::new(sled_crate::open(&path)?);
+        if new {
+            set_table_version(&db, MIGRATIONS[MIGRATIONS.len() - 1].version)?;
+        }
+        connection.push((table.clone(), db.clone()));
+        // close the least recently used dbs which no call holds
+        let mut unused = connection.len().saturating_sub(MAX_OPEN_TABLES);
+        connection.retain(|x| {
+            let close = unused > 0 && Arc::strong_count(&x.1) == 1;
+            unused -= usize::from(close);
+            !close
+        });
+        drop(connection);
+        Ok(db)
+    }
+}
+
+/// The first byte of a sled value encoded by [`encode_value`].
+const VALUE_VERSION: u8 = 2;
+
+/// The sled value of a [`MarsImage`], without the sha which is the key.
+#[derive(Serialize, Deserialize)]
+struct Value {
+    id: i32,
+    phash: Option<u64>,
+    kind: MediaKind,
+    frames: Vec<u64>,
+    duration: Option<u32>,
+    time: Option<i64>,
+}
+
+/// [`Value`] before migration 2, with the first byte `1`.
+#[derive(Serialize, Deserialize)]
+struct ValueV1 {
+    id: i32,
+    phash: Option<u64>,
+    kind: MediaKind,
+    frames: Vec<u64>,
+    duration: Option<u32>,
+}
+
+/// Encode a [`MarsImage`] as `VALUE_VERSION` followed by the bincode of
+/// [`Value`] with fixed size integers, which is always longer than 12 bytes.
+fn encode_value(item: &MarsImage) -> Vec<u8> {
+    let value = Value {
+        id: item.id,
+        phash: item.phash,
+        kind: item.kind,
+        frames: item.frames.clone(),
+        duration: item.duration,
+        time: item.time,
+    };
+    let mut bytes = vec![VALUE_VERSION];
+    bytes.extend(
+        bincode::serde::encode_to_vec(value, bincode::config::legacy())
+            .expect("encode sled value should success"),
+    );
+    bytes
+}
+
+/// Decode a sled value encoded by [`encode_value`].
+fn decode_value(key: &[u8], value: &[u8]) -> Result<MarsImage> {
+    let Some((&VALUE_VERSION, value)) = value.split_first() else {
+        bail!("unknown sled value format: {}", hex::encode(value));
+    };
+    let (value, _): (Value, _) =
+        bincode::serde::decode_from_slice(value, bincode::config::legacy())?;
+    Ok(MarsImage::new(value.id, key)
+        .with_phash(value.phash)
+        .with_video(value.kind, value.frames, value.duration)
+        .with_time(value.time))
+}
+
+fn encode_text(record: TextRecord) -> Vec<u8> {
+    let mut bytes = record.id.into_vec_u8();
+    bytes.extend(record.time.map_or_default(IntoVecU8::into_vec_u8));
+    bytes
+}
+
+fn decode_text(value: &[u8]) -> TextRecord {
+    let (id, time) = value.split_at(4);
+    TextRecord::new(
+        i32::from_vec_u8(id),
+        (!time.is_empty()).then(|| i64::from_vec_u8(time)),
+    )
+}
+
+fn decode_occurrences(value: &[u8]) -> Result<Vec<Occurrence>> {
+    Ok(bincode::serde::decode_from_slice(value, bincode::config::legacy())?.0)
+}
+
+/// The schema version of a chat table, `0` if it is not recorded.
+fn table_version(db: &Db) -> Result<u32> {
+    Ok(db
+        .open_tree(META_TREE)?
+        .get(SCHEMA_VERSION_KEY)?
+        .map_or(0, |x| u32::from_vec_u8(&x)))
+}
+
+fn set_table_version(db: &Db, version: u32) -> Result<()> {
+    db.open_tree(META_TREE)?
+        .insert(SCHEMA_VERSION_KEY, version.into_vec_u8())?;
+    Ok(())
+}
+
+/// Migration 1: older versions stored 4 bytes message id, followed by 8 bytes
+/// perceptual hash if it exists. Values of [`encode_value`] are always longer.
+fn upgrade_legacy_values(db: &Db) -> Result<()> {
+    for x in db.iter() {
+        let (key, value) = x?;
+        if let 4 | 12 = value.len() {
+            let (id, phash) = value.split_at(4);
+            let item = MarsImage::new(i32::from_vec_u8(id), &*key)
+                .with_phash((!phash.is_empty()).then(|| u64::from_vec_u8(phash)));
+            db.insert(key, encode_value(&item))?;
+        }
     }
+    Ok(())
+}
+
+/// Migration 2: re-encode values of migration 1 with the time, and stamp
+/// records without a time with `now`, so they expire one retention later.
+fn stamp_time(db: &Db, now: i64) -> Result<()> {
+    for x in db.iter() {
+        let (key, value) = x?;
+        let item = match value.split_first() {
+            Some((1, value)) => {
+                let (value, _): (ValueV1, _) =
+                    bincode::serde::decode_from_slice(value, bincode::config::legacy())?;
+                MarsImage::new(value.id, &*key)
+                    .with_phash(value.phash)
+                    .with_video(value.kind, value.frames, value.duration)
+            }
+            _ => decode_value(&key, &value)?,
+        };
+        if item.time.is_none() {
+            db.insert(key, encode_value(&item.with_time(Some(now))))?;
+        }
+    }
+    let texts = db.open_tree(TEXT_TREE)?;
+    for x in &texts {
+        let (key, value) = x?;
+        let record = decode_text(&value);
+        if record.time.is_none() {
+            texts.insert(key, encode_text(TextRecord::new(record.id, Some(now))))?;
+        }
+    }
+    Ok(())
 }
 
 impl DbOperation for SledDb {
-    type Connection = Db;
-    fn create_table_if_not_exist(&self, table: &str) -> Self::Connection {
-        self.get_table(table).map_or_else(
-            || {
-                self.connect(table);
-                self.get_table(table)
-                    .expect("table must exist after connect")
-            },
-            |temp| temp,
-        )
+    fn create_table_if_not_exist(&self, table: &ChatKey) {
+        if let Err(e) = self.open_table(table) {
+            error!("open sled db of chat {table} failed: {e:?}");
+        }
     }
 
-    fn query_from_table(&self, table: &str, key: &[u8]) -> Result<Option<MarsImage>> {
-        let db = self.get_table(table);
-        if let Some(db) = db {
-            Ok(db
-                .get(key)?
-                .map(|x| i32::from_vec_u8(x.as_ref()))
-                .map(|x| MarsImage::new(x, key)))
-        } else {
-            Ok(None)
+    fn query_from_table(&self, table: &ChatKey, key: &[u8]) -> Result<Option<MarsImage>> {
+        if !self.exist_table(table)? {
+            return Ok(None);
         }
+        let db = self.open_table(table)?;
+        db.get(key)?.map(|x| decode_value(key, &x)).transpose()
     }
 
     /// This function will return Ok even if the key has already existed
-    fn insert_to_table(&self, table: &str, item: MarsImage) -> Result<()> {
-        let db = self.create_table_if_not_exist(table);
-        let _value = db.insert(item.sha.clone(), item.id.into_vec_u8())?;
+    fn insert_to_table(&self, table: &ChatKey, item: MarsImage) -> Result<()> {
+        let db = self.open_table(table)?;
+        let _value = db.insert(item.sha.clone(), encode_value(&item))?;
         Ok(())
     }
 
-    fn insert_or_get_existing(&self, table: &str, item: MarsImage) -> Result<Option<MarsImage>> {
-        let db = self.create_table_if_not_exist(table);
+    fn insert_or_get_existing(
+        &self,
+        table: &ChatKey,
+        item: MarsImage,
+    ) -> Result<Option<MarsImage>> {
+        let db = self.open_table(table)?;
         let exists = db.get(item.sha.clone())?;
-        if let Some(id) = exists {
-            return Ok(Some(MarsImage::new(i32::from_vec_u8(&id), item.sha)));
+        if let Some(value) = exists {
+            return decode_value(&item.sha, &value).map(Some);
         }
-        let value = db.insert(item.sha.clone(), item.id.into_vec_u8())?;
+        let value = db.insert(item.sha.clone(), encode_value(&item))?;
         debug_assert!(value.is_none());
         Ok(None)
     }
 
-    fn drop_table(&self, table: &str) -> Result<()> {
-        std::fs::remove_dir_all(self.path.join(table))?;
+    fn query_all_from_table(&self, table: &ChatKey) -> Result<Vec<MarsImage>> {
+        if !self.exist_table(table)? {
+            return Ok(vec![]);
+        }
+        let db = self.open_table(table)?;
+        db.iter()
+            .map(|x| {
+                let (key, value) = x?;
+                decode_value(&key, &value)
+            })
+            .collect()
+    }
+
+    fn query_by_unique_id(&self, table: &ChatKey, unique_id: &str) -> Result<Option<MarsImage>> {
+        if !self.exist_table(table)? {
+            return Ok(None);
+        }
+        let db = self.open_table(table)?;
+        let Some(sha) = db.open_tree(UNIQUE_ID_TREE)?.get(unique_id)? else {
+            return Ok(None);
+        };
+        db.get(&sha)?.map(|x| decode_value(&sha, &x)).transpose()
+    }
+
+    fn insert_unique_id(&self, table: &ChatKey, unique_id: &str, sha: &[u8]) -> Result<()> {
+        let db = self.open_table(table)?;
+        db.open_tree(UNIQUE_ID_TREE)?.insert(unique_id, sha)?;
+        Ok(())
+    }
+
+    fn query_all_unique_ids(&self, table: &ChatKey) -> Result<Vec<(String, Vec<u8>)>> {
+        if !self.exist_table(table)? {
+            return Ok(vec![]);
+        }
+        let db = self.open_table(table)?;
+        let tree = db.open_tree(UNIQUE_ID_TREE)?;
+        tree.iter()
+            .map(|x| {
+                let (key, value) = x?;
+                Ok((String::from_utf8(key.to_vec())?, value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn query_all_texts(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, TextRecord)>> {
+        if !self.exist_table(table)? {
+            return Ok(vec![]);
+        }
+        let db = self.open_table(table)?;
+        let tree = db.open_tree(TEXT_TREE)?;
+        tree.iter()
+            .map(|x| {
+                let (key, value) = x?;
+                Ok((key.to_vec(), decode_text(&value)))
+            })
+            .collect()
+    }
+
+    fn query_all_occurrences(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, Vec<Occurrence>)>> {
+        if !self.exist_table(table)? {
+            return Ok(vec![]);
+        }
+        let db = self.open_table(table)?;
+        let tree = db.open_tree(OCCURRENCE_TREE)?;
+        tree.iter()
+            .map(|x| {
+                let (key, value) = x?;
+                Ok((key.to_vec(), decode_occurrences(&value)?))
+            })
+            .collect()
+    }
+
+    fn insert_or_get_existing_text(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        record: TextRecord,
+    ) -> Result<Option<TextRecord>> {
+        let db = self.open_table(table)?;
+        let tree = db.open_tree(TEXT_TREE)?;
+        Ok(tree
+            .compare_and_swap(sha, None::<&[u8]>, Some(encode_text(record)))?
+            .err()
+            .and_then(|e| e.current)
+            .map(|x| decode_text(&x)))
+    }
+
+    fn add_occurrence(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        occurrence: Occurrence,
+    ) -> Result<Vec<Occurrence>> {
+        let db = self.open_table(table)?;
+        let tree = db.open_tree(OCCURRENCE_TREE)?;
+        loop {
+            let current = tree.get(sha)?;
+            let mut occurrences = current
+                .as_deref()
+                .map(decode_occurrences)
+                .transpose()?
+                .unwrap_or_default();
+            if let Err(index) = occurrences.binary_search_by_key(&occurrence.id, |x| x.id) {
+                occurrences.insert(index, occurrence);
+            } else {
+                return Ok(occurrences);
+            }
+            let new = bincode::serde::encode_to_vec(&occurrences, bincode::config::legacy())?;
+            // retry if another message is recorded at the same time
+            if tree.compare_and_swap(sha, current, Some(new))?.is_ok() {
+                return Ok(occurrences);
+            }
+        }
+    }
+
+    fn purge_expired(&self, table: &ChatKey, before: i64) -> Result<usize> {
+        if !self.exist_table(table)? {
+            return Ok(0);
+        }
+        let db = self.open_table(table)?;
+        let before = Some(before);
+        let mut purged = 0;
+        for x in db.iter() {
+            let (key, value) = x?;
+            if is_expired(decode_value(&key, &value)?.time, before) {
+                db.remove(key)?;
+                purged += 1;
+            }
+        }
+        let texts = db.open_tree(TEXT_TREE)?;
+        for x in &texts {
+            let (key, value) = x?;
+            if is_expired(decode_text(&value).time, before) {
+                texts.remove(key)?;
+                purged += 1;
+            }
+        }
+        let unique_ids = db.open_tree(UNIQUE_ID_TREE)?;
+        for x in &unique_ids {
+            let (key, sha) = x?;
+            if !db.contains_key(sha)? {
+                unique_ids.remove(key)?;
+            }
+        }
+        let occurrences = db.open_tree(OCCURRENCE_TREE)?;
+        for x in &occurrences {
+            let (key, value) = x?;
+            let mut list = decode_occurrences(&value)?;
+            let len = list.len();
+            list.retain(|x| !is_expired(x.time, before));
+            if list.len() == len {
+                continue;
+            }
+            let new = (!list.is_empty())
+                .then(|| bincode::serde::encode_to_vec(&list, bincode::config::legacy()))
+                .transpose()?;
+            // an occurrence added meanwhile is kept, and purged next time
+            let _ = occurrences.compare_and_swap(key, Some(value), new)?;
+        }
+        Ok(purged)
+    }
+
+    fn remove_by_key(&self, table: &ChatKey, sha: &[u8]) -> Result<usize> {
+        if !self.exist_table(table)? {
+            return Ok(0);
+        }
+        let db = self.open_table(table)?;
+        let mut removed = usize::from(db.remove(sha)?.is_some());
+        removed += usize::from(db.open_tree(TEXT_TREE)?.remove(sha)?.is_some());
+        let unique_ids = db.open_tree(UNIQUE_ID_TREE)?;
+        for x in &unique_ids {
+            let (key, value) = x?;
+            if *value == *sha {
+                unique_ids.remove(key)?;
+            }
+        }
+        db.open_tree(OCCURRENCE_TREE)?.remove(sha)?;
+        Ok(removed)
+    }
+
+    fn compact_table(&self, table: &ChatKey) -> Result<usize> {
+        if !self.exist_table(table)? {
+            return Ok(0);
+        }
+        let db = self.open_table(table)?;
+        let mut seen = HashSet::new();
+        let mut removed = 0;
+        for x in db.iter() {
+            let (key, value) = x?;
+            if !seen.insert(decode_value(&key, &value)?.id) {
+                db.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn index(&self) -> &FingerprintIndex {
+        &self.index
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &MIGRATIONS
+    }
+
+    /// The lowest schema version of all chat tables.
+    fn schema_version(&self) -> Result<u32> {
+        let mut version = MIGRATIONS[MIGRATIONS.len() - 1].version;
+        for table in self.list_tables()? {
+            version = version.min(table_version(&*self.open_table(&table)?)?);
+        }
+        Ok(version)
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        for table in self.list_tables()? {
+            let db = self.open_table(&table)?;
+            if table_version(&db)? >= migration.version {
+                continue;
+            }
+            match migration.version {
+                1 => upgrade_legacy_values(&db)?,
+                2 => stamp_time(&db, now())?,
+                x => bail!("unknown sled migration: {x}"),
+            }
+            set_table_version(&db, migration.version)?;
+            db.flush()?;
+        }
+        Ok(())
+    }
+
+    fn query_settings(&self, table: &ChatKey) -> Result<ChatSettings> {
+        if !self.exist_table(table)? {
+            return Ok(ChatSettings::default());
+        }
+        Ok(
+            match self
+                .open_table(table)?
+                .open_tree(META_TREE)?
+                .get(SETTINGS_KEY)?
+            {
+                Some(value) => serde_json::from_slice(&value)?,
+                None => ChatSettings::default(),
+            },
+        )
+    }
+
+    fn save_settings(&self, table: &ChatKey, settings: &ChatSettings) -> Result<()> {
+        self.open_table(table)?
+            .open_tree(META_TREE)?
+            .insert(SETTINGS_KEY, serde_json::to_vec(settings)?)?;
+        Ok(())
+    }
+
+    fn schedule_deletion(&self, deletion: &PendingDeletion) -> Result<()> {
+        self.deletions()?
+            .insert(deletion_key(deletion), serde_json::to_vec(deletion)?)?;
         Ok(())
     }
 
-    fn exist_table(&self, table: &str) -> Result<bool> {
-        Ok(std::fs::exists(self.path.join(table))?)
+    fn query_due_deletions(&self, now: i64) -> Result<Vec<PendingDeletion>> {
+        let mut deletions = vec![];
+        for entry in self.deletions()?.iter() {
+            let deletion: PendingDeletion = serde_json::from_slice(&entry?.1)?;
+            if deletion.due <= now {
+                deletions.push(deletion);
+            }
+        }
+        deletions.sort_by_key(|x| x.due);
+        Ok(deletions)
+    }
+
+    fn remove_deletion(&self, deletion: &PendingDeletion) -> Result<()> {
+        self.deletions()?.remove(deletion_key(deletion))?;
+        Ok(())
+    }
+
+    /// A chat which never recorded anything has no dir, and is dropped as
+    /// well.
+    fn drop_table(&self, table: &ChatKey) -> Result<()> {
+        // a cached db would still write to the removed table
+        self.connection.lock().unwrap().retain(|x| x.0 != *table);
+        match std::fs::remove_dir_all(self.path.join(table.as_str())) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            x => Ok(x?),
+        }
+    }
+
+    fn exist_table(&self, table: &ChatKey) -> Result<bool> {
+        Ok(std::fs::exists(self.path.join(table.as_str()))?)
+    }
+
+    /// Directories which are not named by a chat id are not chat tables. A
+    /// chat id written otherwise, e.g. `0123`, is not the path of its chat, so
+    /// it is skipped as well.
+    fn list_tables(&self) -> Result<Vec<ChatKey>> {
+        let mut tables = vec![];
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match name.parse::<ChatKey>() {
+                Ok(table) if table.as_str() == name => tables.push(table),
+                Ok(table) => warn!("skip dir `{name}`, which is not the path of chat {table}"),
+                Err(_) => (),
+            }
+        }
+        Ok(tables)
+    }
+}
+
+fn deletion_key(deletion: &PendingDeletion) -> String {
+    format!("{}/{}", deletion.chat, deletion.message_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{inspect_migrations, Backend};
+
+    #[test]
+    fn test_inspect_creates_nothing() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = SledDb::new(tempdir.path());
+        db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+            .unwrap();
+        drop(db);
+        let (version, pending) = inspect_migrations(Backend::Sled, tempdir.path()).unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(pending, Vec::<&Migration>::new());
+        assert!(!tempdir.path().join(DELETION_DB).exists());
+        let missing = tempdir.path().join("missing");
+        assert!(inspect_migrations(Backend::Sled, &missing).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_held_table_is_not_closed() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = SledDb::new(tempdir.path());
+        // a db still used by another call
+        let held = db.open_table(&0.into()).unwrap();
+        for table in 1..=i64::try_from(MAX_OPEN_TABLES).unwrap() {
+            db.create_table_if_not_exist(&table.into());
+        }
+        let cached = |table: i64| {
+            db.connection
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|x| x.0 == table.into())
+        };
+        assert!(cached(0));
+        assert!(!cached(1));
+        assert!(Arc::ptr_eq(&held, &db.open_table(&0.into()).unwrap()));
+        db.insert_to_table(&1.into(), MarsImage::new(1, [1]))
+            .unwrap();
+        assert_eq!(
+            db.query_all_from_table(&1.into()).unwrap(),
+            vec![MarsImage::new(1, [1])]
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy_values() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let db = SledDb::new(tempdir.path());
+        let table = db.open_table(&123.into()).unwrap();
+        table.insert([1], 42.into_vec_u8()).unwrap();
+        let mut value = 43.into_vec_u8();
+        value.extend(7_u64.into_vec_u8());
+        table.insert([2], value).unwrap();
+        table
+            .open_tree(TEXT_TREE)
+            .unwrap()
+            .insert([3], 44.into_vec_u8())
+            .unwrap();
+        table.drop_tree(META_TREE).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(
+            db.pending_migrations().unwrap(),
+            vec![&MIGRATIONS[0], &MIGRATIONS[1]]
+        );
+        assert!(db.query_from_table(&123.into(), &[1]).is_err());
+
+        let start = now();
+        assert_eq!(db.migrate().unwrap(), vec![&MIGRATIONS[0], &MIGRATIONS[1]]);
+        assert_eq!(db.pending_migrations().unwrap(), Vec::<&Migration>::new());
+        let item = db.query_from_table(&123.into(), &[1]).unwrap().unwrap();
+        let time = item.time.unwrap();
+        assert!(time >= start);
+        assert_eq!(item, MarsImage::new(42, [1]).with_time(Some(time)));
+        assert_eq!(
+            db.query_from_table(&123.into(), &[2]).unwrap(),
+            Some(
+                MarsImage::new(43, [2])
+                    .with_phash(Some(7))
+                    .with_time(Some(time))
+            )
+        );
+        assert_eq!(
+            db.query_all_texts(&123.into()).unwrap(),
+            vec![(vec![3], TextRecord::new(44, Some(time)))]
+        );
+        assert!(decode_value(&[1], &[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_encode_value() {
+        let item = MarsImage::new(-1, [1]).with_phash(Some(0));
+        let value = encode_value(&item);
+        assert!(value.len() > 12);
+        assert_eq!(decode_value(&[1], &value).unwrap(), item);
+        for record in [TextRecord::new(1, None), TextRecord::new(-1, Some(-2))] {
+            assert_eq!(decode_text(&encode_text(record)), record);
+        }
+    }
+
+    #[test]
+    fn test_dirs_not_named_by_chat_id() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let root = tempdir.path().join("db");
+        let db = SledDb::new(&root);
+        db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+            .unwrap();
+        for name in ["..", "../123", "not-a-chat", "123 "] {
+            assert!(name.parse::<ChatKey>().is_err(), "{name}");
+        }
+        std::fs::create_dir(root.join("not-a-chat")).unwrap();
+        // parsed as chat 123, but not its path
+        std::fs::create_dir(root.join("0123")).unwrap();
+        std::fs::create_dir(tempdir.path().join("123")).unwrap();
+        assert_eq!(db.list_tables().unwrap(), vec![ChatKey::from(123)]);
+        db.drop_table(&123.into()).unwrap();
+        assert_eq!(db.list_tables().unwrap(), vec![]);
+        assert!(root.join("not-a-chat").exists());
+        assert!(tempdir.path().join("123").exists());
     }
 }
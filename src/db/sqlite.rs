@@ -1,71 +1,413 @@
 //! The implemention for binary db backend.
+//!
+//! Records of all chats are kept in the same tables, keyed by `chat_id`.
+//! Versions before schema version 3 created a table per chat instead, which
+//! are called legacy tables here.
 
-use std::{path::Path, sync::Mutex};
+// prepared statements borrow the locked connection until they are dropped
+#![allow(clippy::significant_drop_tightening)]
 
-use anyhow::Result;
-use rusqlite::params;
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use super::{DbOperation, MarsImage};
+use anyhow::{bail, ensure, Result};
+use rusqlite::{params, Connection, OpenFlags};
+
+use super::{
+    index::FingerprintIndex, retention::now, ChatKey, DbOperation, MarsImage, MediaKind, Migration,
+    Occurrence, PendingDeletion, TextRecord,
+};
+use crate::config::ChatSettings;
+
+/// The tables of the latest schema version. `chats` lists every chat with
+/// records, as the chat tables of sled.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS chats (
+        chat_id TEXT NOT NULL PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS images (
+        chat_id TEXT NOT NULL,
+        sha BLOB NOT NULL,
+        msg_id INTEGER,
+        phash INTEGER,
+        kind INTEGER NOT NULL DEFAULT 0,
+        frames BLOB,
+        duration INTEGER,
+        time INTEGER,
+        PRIMARY KEY (chat_id, sha)
+    );
+    CREATE INDEX IF NOT EXISTS images_msg_id ON images (chat_id, msg_id);
+    CREATE INDEX IF NOT EXISTS images_time ON images (chat_id, time);
+    CREATE TABLE IF NOT EXISTS file_unique_ids (
+        chat_id TEXT NOT NULL,
+        unique_id TEXT NOT NULL,
+        sha BLOB NOT NULL,
+        PRIMARY KEY (chat_id, unique_id)
+    );
+    CREATE TABLE IF NOT EXISTS texts (
+        chat_id TEXT NOT NULL,
+        sha BLOB NOT NULL,
+        msg_id INTEGER NOT NULL,
+        time INTEGER,
+        PRIMARY KEY (chat_id, sha)
+    );
+    CREATE TABLE IF NOT EXISTS occurrences (
+        chat_id TEXT NOT NULL,
+        sha BLOB NOT NULL,
+        msg_id INTEGER NOT NULL,
+        time INTEGER,
+        sender INTEGER,
+        PRIMARY KEY (chat_id, sha, msg_id)
+    );
+    CREATE TABLE IF NOT EXISTS settings (
+        chat_id TEXT NOT NULL PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS deletions (
+        chat_id TEXT NOT NULL,
+        msg_id INTEGER NOT NULL,
+        due INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, msg_id)
+    );
+    CREATE INDEX IF NOT EXISTS deletions_due ON deletions (due);
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT NOT NULL PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+/// The tables which have a `chat_id` column, except `chats` and `deletions`,
+/// whose rows outlive the records of a chat.
+const CHAT_TABLES: [&str; 5] = [
+    "images",
+    "file_unique_ids",
+    "texts",
+    "occurrences",
+    "settings",
+];
+
+const MIGRATIONS: [Migration; 3] = [
+    Migration {
+        version: 1,
+        description: "add columns phash, kind, frames and duration to chat tables",
+    },
+    Migration {
+        version: 2,
+        description: "add column time to chat tables and texts, records of older versions are stamped with the migration time",
+    },
+    Migration {
+        version: 3,
+        description: "move records of all chat tables into table images",
+    },
+];
 
 pub struct Sqlite {
-    pub inner: Mutex<rusqlite::Connection>,
+    pub inner: Mutex<Connection>,
+    index: FingerprintIndex,
 }
 
 impl Sqlite {
+    /// Open a sqlite database file. An existing file is opened as is, such as
+    /// the database of older versions. Otherwise if `path` is a directory or
+    /// has no extension, the file `mars.sqlite` inside it is used.
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = file_path(path.as_ref());
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Open an existing database file read only, without creating any table,
+    /// to inspect its schema version.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let path = file_path(path.as_ref());
+        ensure!(
+            path.is_file(),
+            "database file `{}` does not exist",
+            path.display()
+        );
         Ok(Self {
-            inner: Mutex::new(rusqlite::Connection::open(path)?),
+            inner: Mutex::new(Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?),
+            index: FingerprintIndex::default(),
         })
     }
 
+    #[cfg(test)]
     pub fn new_memory() -> Self {
-        Self {
-            inner: Mutex::new(
-                rusqlite::Connection::open_in_memory().expect("open in memory should success"),
-            ),
+        Self::init(Connection::open_in_memory().expect("open in memory should success"))
+            .expect("init in memory db should success")
+    }
+
+    fn init(connection: Connection) -> Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        let db = Self {
+            inner: Mutex::new(connection),
+            index: FingerprintIndex::default(),
+        };
+        // a new database is at the latest schema version
+        if db.query_schema_version()?.is_none() && db.legacy_tables()?.is_empty() {
+            db.set_schema_version(MIGRATIONS[MIGRATIONS.len() - 1].version)?;
+        }
+        Ok(db)
+    }
+
+    fn query_schema_version(&self) -> Result<Option<u32>> {
+        let lock = self.inner.lock().unwrap();
+        // a database of older versions opened read only has no `meta`
+        if !has_table(&lock, "meta")? {
+            return Ok(None);
+        }
+        let mut stmt =
+            lock.prepare_cached("SELECT value FROM meta WHERE key = 'schema_version'")?;
+        let mut rows = stmt.query([])?;
+        Ok(rows.next()?.map(|x| x.get(0)).transpose()?)
+    }
+
+    fn set_schema_version(&self, version: u32) -> Result<()> {
+        self.inner.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?)",
+            params![version],
+        )?;
+        Ok(())
+    }
+
+    /// The chat tables created before schema version 3, by their names and
+    /// chats. A name may differ from the chat id, e.g. `0123`. Tables which
+    /// are not named by a chat id are never chat tables, so their names are
+    /// safe in SQL.
+    fn legacy_tables(&self) -> Result<Vec<(String, ChatKey)>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut tables = vec![];
+        for name in rows {
+            let name = name?;
+            if let Ok(table) = name.parse() {
+                tables.push((name, table));
+            }
         }
+        Ok(tables)
     }
+
+    /// Migration 1 and 2: tables created by older versions do not have the
+    /// new `columns`.
+    fn add_columns(&self, table: &str, columns: &[(&str, &str)]) -> Result<()> {
+        let lock = self.inner.lock().unwrap();
+        for (column, definition) in columns {
+            if !has_column(&lock, table, column)? {
+                lock.execute(
+                    &format!("ALTER TABLE [{table}] ADD COLUMN {column} {definition}"),
+                    [],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Migration 3: copy all legacy tables into `images` and drop them, and
+    /// rename the columns `chat` and `id` of the other tables.
+    fn merge_legacy_tables(&self) -> Result<()> {
+        let legacy = self.legacy_tables()?;
+        let lock = self.inner.lock().unwrap();
+        let transaction = lock.unchecked_transaction()?;
+        for (name, table) in legacy {
+            transaction.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO images
+                    (chat_id, sha, msg_id, phash, kind, frames, duration, time)
+                    SELECT ?, sha, id, phash, kind, frames, duration, time FROM [{name}]"
+                ),
+                params![table.as_str()],
+            )?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO chats (chat_id) VALUES (?)",
+                params![table.as_str()],
+            )?;
+            transaction.execute(&format!("DROP TABLE [{name}]"), [])?;
+        }
+        for table in ["file_unique_ids", "texts", "occurrences"] {
+            for (from, to) in [("chat", "chat_id"), ("id", "msg_id")] {
+                if has_column(&transaction, table, from)? {
+                    transaction.execute(
+                        &format!("ALTER TABLE {table} RENAME COLUMN {from} TO {to}"),
+                        [],
+                    )?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+fn has_table(connection: &Connection, table: &str) -> Result<bool> {
+    Ok(connection.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+        params![table],
+        |row| row.get(0),
+    )?)
+}
+
+/// The database file at `path`: `path` itself if it is a file or has an
+/// extension, otherwise `mars.sqlite` inside it.
+fn file_path(path: &Path) -> PathBuf {
+    if path.is_file() || (!path.is_dir() && path.extension().is_some()) {
+        path.to_path_buf()
+    } else {
+        path.join("mars.sqlite")
+    }
+}
+
+fn has_column(connection: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(connection.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?)
+}
+
+/// Columns added by migration 1, with their definitions.
+const ADDED_COLUMNS: [(&str, &str); 4] = [
+    ("phash", "INTEGER"),
+    ("kind", "INTEGER NOT NULL DEFAULT 0"),
+    ("frames", "BLOB"),
+    ("duration", "INTEGER"),
+];
+
+/// The column added by migration 2, to chat tables and `texts`.
+const TIME_COLUMN: [(&str, &str); 1] = [("time", "INTEGER")];
+
+fn encode_frames(frames: &[u64]) -> Option<Vec<u8>> {
+    (!frames.is_empty()).then(|| frames.iter().flat_map(|x| x.to_le_bytes()).collect())
+}
+
+/// sqlite has no unsigned 64-bit integer, so the perceptual hash is stored as
+/// its bit-identical `i64`. Frames are stored as little-endian `u64`s.
+///
+/// The columns are `msg_id, sha, phash, kind, frames, duration, time`.
+fn row_to_image(row: &rusqlite::Row<'_>) -> MarsImage {
+    let kind: u8 = row.get(3).expect("Failed to get kind from row");
+    let frames: Option<Vec<u8>> = row.get(4).expect("Failed to get frames from row");
+    MarsImage::new(
+        row.get(0).expect("Failed to get id from row"),
+        row.get::<_, Vec<u8>>(1)
+            .expect("Failed to get sha from row"),
+    )
+    .with_phash(
+        row.get::<_, Option<i64>>(2)
+            .expect("Failed to get phash from row")
+            .map(i64::cast_unsigned),
+    )
+    .with_video(
+        MediaKind::from_u8(kind).unwrap_or_default(),
+        frames
+            .unwrap_or_default()
+            .as_chunks::<8>()
+            .0
+            .iter()
+            .map(|x| u64::from_le_bytes(*x))
+            .collect(),
+        row.get(5).expect("Failed to get duration from row"),
+    )
+    .with_time(row.get(6).expect("Failed to get time from row"))
 }
 
 impl DbOperation for Sqlite {
-    type Connection = ();
-    fn create_table_if_not_exist(&self, table: &str) {
-        let query = format!(
-            "CREATE TABLE IF NOT EXISTS [{table}] (
-                id INTEGER,
-                sha BLOB NOT NULL PRIMARY KEY
-            );"
-        );
+    fn create_table_if_not_exist(&self, table: &ChatKey) {
         self.inner
             .lock()
             .unwrap()
-            .execute(&query, [])
+            .prepare_cached("INSERT OR IGNORE INTO chats (chat_id) VALUES (?)")
+            .and_then(|mut x| x.execute(params![table.as_str()]))
             .expect("Table creation failed");
     }
 
-    fn query_from_table(&self, table: &str, sha: &[u8]) -> Result<Option<MarsImage>> {
-        let query = format!("SELECT id, sha FROM [{table}] WHERE sha = ?");
+    fn query_from_table(&self, table: &ChatKey, sha: &[u8]) -> Result<Option<MarsImage>> {
         let lock = self.inner.lock().unwrap();
-        let mut stmt = lock.prepare(&query)?;
-        let mut rows = stmt.query(params![sha])?;
-
-        Ok(rows.next()?.map(|row| MarsImage {
-            id: row.get(0).expect("Failed to get id from row"),
-            sha: row.get(1).expect("Failed to get sha from row"),
-        }))
+        let mut stmt = lock.prepare_cached(
+            "SELECT msg_id, sha, phash, kind, frames, duration, time FROM images
+            WHERE chat_id = ?1 AND sha = ?2",
+        )?;
+        let mut rows = stmt.query(params![table.as_str(), sha])?;
+        Ok(rows.next()?.map(row_to_image))
     }
 
-    fn insert_to_table(&self, table: &str, item: MarsImage) -> Result<()> {
+    fn insert_to_table(&self, table: &ChatKey, item: MarsImage) -> Result<()> {
         self.create_table_if_not_exist(table);
-        let query = format!("INSERT INTO [{table}] (id, sha) VALUES (?1, ?2)");
         self.inner
             .lock()
             .unwrap()
-            .execute(&query, params![item.id, item.sha])?;
+            .prepare_cached(
+                "INSERT INTO images (chat_id, msg_id, sha, phash, kind, frames, duration, time)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                table.as_str(),
+                item.id,
+                item.sha,
+                item.phash.map(u64::cast_signed),
+                item.kind as u8,
+                encode_frames(&item.frames),
+                item.duration,
+                item.time
+            ])?;
         Ok(())
     }
 
-    fn insert_or_get_existing(&self, table: &str, item: MarsImage) -> Result<Option<MarsImage>> {
+    fn insert_many(
+        &self,
+        table: &ChatKey,
+        items: Vec<MarsImage>,
+    ) -> Result<Vec<Option<MarsImage>>> {
+        self.create_table_if_not_exist(table);
+        let mut lock = self.inner.lock().unwrap();
+        // one transaction, so the batch is synced to disk once
+        let transaction = lock.transaction()?;
+        let mut result = Vec::with_capacity(items.len());
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR IGNORE INTO images (chat_id, msg_id, sha, phash, kind, frames, duration, time)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            let mut query = transaction.prepare_cached(
+                "SELECT msg_id, sha, phash, kind, frames, duration, time FROM images
+                WHERE chat_id = ?1 AND sha = ?2",
+            )?;
+            for item in items {
+                let inserted = insert.execute(params![
+                    table.as_str(),
+                    item.id,
+                    item.sha,
+                    item.phash.map(u64::cast_signed),
+                    item.kind as u8,
+                    encode_frames(&item.frames),
+                    item.duration,
+                    item.time
+                ])?;
+                result.push(if inserted > 0 {
+                    None
+                } else {
+                    Some(query.query_row(params![table.as_str(), item.sha], |row| {
+                        Ok(row_to_image(row))
+                    })?)
+                });
+            }
+        }
+        transaction.commit()?;
+        Ok(result)
+    }
+
+    fn insert_or_get_existing(
+        &self,
+        table: &ChatKey,
+        item: MarsImage,
+    ) -> Result<Option<MarsImage>> {
         let sha = item.sha.clone();
         let result = self.insert_to_table(table, item);
         match result.map_err(|e| {
@@ -86,16 +428,523 @@ impl DbOperation for Sqlite {
         }
     }
 
-    fn drop_table(&self, table: &str) -> Result<()> {
-        let query = format!("DROP TABLE [{table}]");
-        self.inner.lock().unwrap().execute(&query, params![])?;
+    fn query_all_from_table(&self, table: &ChatKey) -> Result<Vec<MarsImage>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare_cached(
+            "SELECT msg_id, sha, phash, kind, frames, duration, time FROM images
+            WHERE chat_id = ?",
+        )?;
+        let rows = stmt.query_map(params![table.as_str()], |row| Ok(row_to_image(row)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn compact_table(&self, table: &ChatKey) -> Result<usize> {
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .prepare_cached(
+                "DELETE FROM images WHERE chat_id = ?1 AND rowid NOT IN
+                (SELECT MIN(rowid) FROM images WHERE chat_id = ?1 GROUP BY msg_id)",
+            )?
+            .execute(params![table.as_str()])?)
+    }
+
+    fn query_by_unique_id(&self, table: &ChatKey, unique_id: &str) -> Result<Option<MarsImage>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare_cached(
+            "SELECT i.msg_id, i.sha, i.phash, i.kind, i.frames, i.duration, i.time
+            FROM file_unique_ids u JOIN images i ON i.chat_id = u.chat_id AND i.sha = u.sha
+            WHERE u.chat_id = ?1 AND u.unique_id = ?2",
+        )?;
+        let mut rows = stmt.query(params![table.as_str(), unique_id])?;
+        Ok(rows.next()?.map(row_to_image))
+    }
+
+    fn insert_unique_id(&self, table: &ChatKey, unique_id: &str, sha: &[u8]) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .prepare_cached(
+                "INSERT OR REPLACE INTO file_unique_ids (chat_id, unique_id, sha)
+                VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![table.as_str(), unique_id, sha])?;
+        Ok(())
+    }
+
+    fn query_all_unique_ids(&self, table: &ChatKey) -> Result<Vec<(String, Vec<u8>)>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt =
+            lock.prepare_cached("SELECT unique_id, sha FROM file_unique_ids WHERE chat_id = ?")?;
+        let rows = stmt.query_map(params![table.as_str()], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn query_all_texts(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, TextRecord)>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt =
+            lock.prepare_cached("SELECT sha, msg_id, time FROM texts WHERE chat_id = ?")?;
+        let rows = stmt.query_map(params![table.as_str()], |row| {
+            Ok((row.get(0)?, TextRecord::new(row.get(1)?, row.get(2)?)))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn query_all_occurrences(&self, table: &ChatKey) -> Result<Vec<(Vec<u8>, Vec<Occurrence>)>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare_cached(
+            "SELECT sha, msg_id, time, sender FROM occurrences WHERE chat_id = ?
+            ORDER BY sha, msg_id",
+        )?;
+        let rows = stmt.query_map(params![table.as_str()], |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                Occurrence::new(row.get(1)?, row.get(2)?, row.get(3)?),
+            ))
+        })?;
+        let mut result: Vec<(Vec<u8>, Vec<Occurrence>)> = vec![];
+        for row in rows {
+            let (sha, occurrence) = row?;
+            match result.last_mut() {
+                Some((last, occurrences)) if *last == sha => occurrences.push(occurrence),
+                _ => result.push((sha, vec![occurrence])),
+            }
+        }
+        Ok(result)
+    }
+
+    fn insert_or_get_existing_text(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        record: TextRecord,
+    ) -> Result<Option<TextRecord>> {
+        // every chat with records is listed, as in sled
+        self.create_table_if_not_exist(table);
+        let lock = self.inner.lock().unwrap();
+        let inserted = lock
+            .prepare_cached(
+                "INSERT OR IGNORE INTO texts (chat_id, sha, msg_id, time) VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![table.as_str(), sha, record.id, record.time])?;
+        if inserted > 0 {
+            return Ok(None);
+        }
+        let mut stmt =
+            lock.prepare_cached("SELECT msg_id, time FROM texts WHERE chat_id = ?1 AND sha = ?2")?;
+        Ok(Some(
+            stmt.query_row(params![table.as_str(), sha], |row| {
+                Ok(TextRecord::new(row.get(0)?, row.get(1)?))
+            })?,
+        ))
+    }
+
+    fn add_occurrence(
+        &self,
+        table: &ChatKey,
+        sha: &[u8],
+        occurrence: Occurrence,
+    ) -> Result<Vec<Occurrence>> {
+        self.create_table_if_not_exist(table);
+        let lock = self.inner.lock().unwrap();
+        lock.prepare_cached(
+            "INSERT OR IGNORE INTO occurrences (chat_id, sha, msg_id, time, sender)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?
+        .execute(params![
+            table.as_str(),
+            sha,
+            occurrence.id,
+            occurrence.time,
+            occurrence.sender
+        ])?;
+        let mut stmt = lock.prepare_cached(
+            "SELECT msg_id, time, sender FROM occurrences WHERE chat_id = ?1 AND sha = ?2
+            ORDER BY msg_id",
+        )?;
+        let rows = stmt.query_map(params![table.as_str(), sha], |row| {
+            Ok(Occurrence::new(row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn index(&self) -> &FingerprintIndex {
+        &self.index
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &MIGRATIONS
+    }
+
+    /// A database without version or legacy tables is new, which is set to
+    /// the latest version when it is not opened read only.
+    fn schema_version(&self) -> Result<u32> {
+        Ok(match self.query_schema_version()? {
+            Some(x) => x,
+            None if self.legacy_tables()?.is_empty() => MIGRATIONS[MIGRATIONS.len() - 1].version,
+            None => 0,
+        })
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        match migration.version {
+            1 => {
+                for (name, _) in self.legacy_tables()? {
+                    self.add_columns(&name, &ADDED_COLUMNS)?;
+                }
+            }
+            2 => {
+                let now = now();
+                self.add_columns("texts", &TIME_COLUMN)?;
+                let mut tables = self
+                    .legacy_tables()?
+                    .into_iter()
+                    .map(|x| x.0)
+                    .collect::<Vec<_>>();
+                for table in &tables {
+                    self.add_columns(table, &TIME_COLUMN)?;
+                }
+                tables.push("texts".to_owned());
+                let lock = self.inner.lock().unwrap();
+                for table in tables {
+                    lock.execute(
+                        &format!("UPDATE [{table}] SET time = ? WHERE time IS NULL"),
+                        params![now],
+                    )?;
+                }
+            }
+            3 => self.merge_legacy_tables()?,
+            x => bail!("unknown sqlite migration: {x}"),
+        }
+        self.set_schema_version(migration.version)
+    }
+
+    fn purge_expired(&self, table: &ChatKey, before: i64) -> Result<usize> {
+        let lock = self.inner.lock().unwrap();
+        let mut purged = lock
+            .prepare_cached("DELETE FROM images WHERE chat_id = ?1 AND time < ?2")?
+            .execute(params![table.as_str(), before])?;
+        purged += lock
+            .prepare_cached("DELETE FROM texts WHERE chat_id = ?1 AND time < ?2")?
+            .execute(params![table.as_str(), before])?;
+        lock.prepare_cached("DELETE FROM occurrences WHERE chat_id = ?1 AND time < ?2")?
+            .execute(params![table.as_str(), before])?;
+        lock.prepare_cached(
+            "DELETE FROM file_unique_ids WHERE chat_id = ?1
+            AND sha NOT IN (SELECT sha FROM images WHERE chat_id = ?1)",
+        )?
+        .execute(params![table.as_str()])?;
+        Ok(purged)
+    }
+
+    fn remove_by_key(&self, table: &ChatKey, sha: &[u8]) -> Result<usize> {
+        let lock = self.inner.lock().unwrap();
+        let transaction = lock.unchecked_transaction()?;
+        let mut removed = transaction
+            .prepare_cached("DELETE FROM images WHERE chat_id = ?1 AND sha = ?2")?
+            .execute(params![table.as_str(), sha])?;
+        removed += transaction
+            .prepare_cached("DELETE FROM texts WHERE chat_id = ?1 AND sha = ?2")?
+            .execute(params![table.as_str(), sha])?;
+        transaction
+            .prepare_cached("DELETE FROM file_unique_ids WHERE chat_id = ?1 AND sha = ?2")?
+            .execute(params![table.as_str(), sha])?;
+        transaction
+            .prepare_cached("DELETE FROM occurrences WHERE chat_id = ?1 AND sha = ?2")?
+            .execute(params![table.as_str(), sha])?;
+        transaction.commit()?;
+        Ok(removed)
+    }
+
+    fn query_settings(&self, table: &ChatKey) -> Result<ChatSettings> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare_cached("SELECT value FROM settings WHERE chat_id = ?")?;
+        let mut rows = stmt.query(params![table.as_str()])?;
+        Ok(match rows.next()? {
+            Some(row) => serde_json::from_str(&row.get::<_, String>(0)?)?,
+            None => ChatSettings::default(),
+        })
+    }
+
+    fn save_settings(&self, table: &ChatKey, settings: &ChatSettings) -> Result<()> {
+        self.create_table_if_not_exist(table);
+        self.inner
+            .lock()
+            .unwrap()
+            .prepare_cached("INSERT OR REPLACE INTO settings (chat_id, value) VALUES (?1, ?2)")?
+            .execute(params![table.as_str(), serde_json::to_string(settings)?])?;
+        Ok(())
+    }
+
+    fn schedule_deletion(&self, deletion: &PendingDeletion) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .prepare_cached(
+                "INSERT OR REPLACE INTO deletions (chat_id, msg_id, due) VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![
+                deletion.chat.as_str(),
+                deletion.message_id,
+                deletion.due
+            ])?;
+        Ok(())
+    }
+
+    fn query_due_deletions(&self, now: i64) -> Result<Vec<PendingDeletion>> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare_cached(
+            "SELECT chat_id, msg_id, due FROM deletions WHERE due <= ? ORDER BY due",
+        )?;
+        let rows = stmt.query_map(params![now], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        let mut deletions = vec![];
+        for row in rows {
+            let (chat, message_id, due) = row?;
+            deletions.push(PendingDeletion::new(chat.parse()?, message_id, due));
+        }
+        Ok(deletions)
+    }
+
+    fn remove_deletion(&self, deletion: &PendingDeletion) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .prepare_cached("DELETE FROM deletions WHERE chat_id = ?1 AND msg_id = ?2")?
+            .execute(params![deletion.chat.as_str(), deletion.message_id])?;
         Ok(())
     }
 
-    fn exist_table(&self, table: &str) -> Result<bool> {
-        let query = "SELECT name FROM sqlite_master WHERE type='table' AND name=?";
+    fn drop_table(&self, table: &ChatKey) -> Result<()> {
+        let lock = self.inner.lock().unwrap();
+        let transaction = lock.unchecked_transaction()?;
+        for chat_table in CHAT_TABLES {
+            transaction.execute(
+                &format!("DELETE FROM {chat_table} WHERE chat_id = ?"),
+                params![table.as_str()],
+            )?;
+        }
+        transaction.execute(
+            "DELETE FROM chats WHERE chat_id = ?",
+            params![table.as_str()],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn exist_table(&self, table: &ChatKey) -> Result<bool> {
+        let lock = self.inner.lock().unwrap();
+        let mut stmt = lock.prepare_cached("SELECT 1 FROM chats WHERE chat_id = ?")?;
+        Ok(stmt.exists(params![table.as_str()])?)
+    }
+
+    fn list_tables(&self) -> Result<Vec<ChatKey>> {
         let lock = self.inner.lock().unwrap();
-        let mut stmt = lock.prepare(query)?;
-        Ok(stmt.exists(params![table])?)
+        let mut stmt = lock.prepare_cached("SELECT chat_id FROM chats")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|x| x?.parse()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{inspect_migrations, Backend};
+
+    #[test]
+    fn test_migrate_old_table() {
+        let db = Sqlite::new_memory();
+        // a chat table of the first version, before schema versioning
+        db.inner
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE [123] (id INTEGER, sha BLOB NOT NULL PRIMARY KEY);
+                INSERT INTO [123] VALUES (1, x'01');
+                DROP TABLE texts;
+                CREATE TABLE texts (chat TEXT NOT NULL, sha BLOB NOT NULL, id INTEGER NOT NULL);
+                INSERT INTO texts VALUES ('123', x'02', 2);
+                DROP TABLE occurrences;
+                CREATE TABLE occurrences (
+                    chat TEXT NOT NULL,
+                    sha BLOB NOT NULL,
+                    id INTEGER NOT NULL,
+                    time INTEGER,
+                    sender INTEGER,
+                    PRIMARY KEY (chat, sha, id)
+                );
+                INSERT INTO occurrences VALUES ('123', x'01', 1, 5, 6);
+                DROP TABLE file_unique_ids;
+                CREATE TABLE file_unique_ids (
+                    chat TEXT NOT NULL,
+                    unique_id TEXT NOT NULL,
+                    sha BLOB NOT NULL,
+                    PRIMARY KEY (chat, unique_id)
+                );
+                INSERT INTO file_unique_ids VALUES ('123', 'AQADx', x'01');
+                DELETE FROM meta;",
+            )
+            .unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(db.pending_migrations().unwrap().len(), 3);
+        assert_eq!(db.query_from_table(&123.into(), &[1]).unwrap(), None);
+
+        let start = now();
+        assert_eq!(db.migrate().unwrap().len(), 3);
+        assert_eq!(db.schema_version().unwrap(), 3);
+        assert_eq!(db.legacy_tables().unwrap(), vec![]);
+        assert_eq!(db.list_tables().unwrap(), vec![ChatKey::from(123)]);
+        let item = db.query_from_table(&123.into(), &[1]).unwrap().unwrap();
+        let time = item.time.unwrap();
+        assert!(time >= start);
+        assert_eq!(item, MarsImage::new(1, [1]).with_time(Some(time)));
+        assert_eq!(
+            db.query_by_unique_id(&123.into(), "AQADx").unwrap(),
+            Some(item)
+        );
+        assert_eq!(
+            db.query_all_texts(&123.into()).unwrap(),
+            vec![(vec![2], TextRecord::new(2, Some(time)))]
+        );
+        assert_eq!(
+            db.query_all_occurrences(&123.into()).unwrap(),
+            vec![(vec![1], vec![Occurrence::new(1, Some(5), Some(6))])]
+        );
+    }
+
+    #[test]
+    fn test_migrate_old_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // the database of older versions is a file without extension
+        let path = dir.path().join("db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE [123] (id INTEGER, sha BLOB NOT NULL PRIMARY KEY);
+                INSERT INTO [123] VALUES (1, x'01');",
+            )
+            .unwrap();
+        let db = Sqlite::new(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(db.migrate().unwrap().len(), 3);
+        assert_eq!(db.list_tables().unwrap(), vec![ChatKey::from(123)]);
+        let item = db.query_from_table(&123.into(), &[1]).unwrap().unwrap();
+        assert_eq!(item.id, 1);
+        drop(db);
+        assert!(path.is_file());
+        assert!(!dir.path().join("mars.sqlite").exists());
+        // it is opened as is again
+        let db = Sqlite::new(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 3);
+        assert!(db.query_from_table(&123.into(), &[1]).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_inspect_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE [123] (id INTEGER, sha BLOB NOT NULL PRIMARY KEY);
+                INSERT INTO [123] VALUES (1, x'01');",
+            )
+            .unwrap();
+        let before = std::fs::read(&path).unwrap();
+        let (version, pending) = inspect_migrations(Backend::Sqlite, &path).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(pending.len(), 3);
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        // nothing is created for a missing database
+        let missing = dir.path().join("missing");
+        assert!(inspect_migrations(Backend::Sqlite, &missing).is_err());
+        assert!(!missing.exists());
+        // a new database is at the latest version
+        drop(Sqlite::new(dir.path().join("new.sqlite")).unwrap());
+        let (version, pending) =
+            inspect_migrations(Backend::Sqlite, dir.path().join("new.sqlite")).unwrap();
+        assert_eq!(version, 3);
+        assert_eq!(pending, Vec::<&Migration>::new());
+    }
+
+    #[test]
+    fn test_chats_are_not_mixed() {
+        let db = Sqlite::new_memory();
+        db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+            .unwrap();
+        db.insert_to_table(&456.into(), MarsImage::new(2, [1]))
+            .unwrap();
+        assert_eq!(
+            db.insert_or_get_existing(&123.into(), MarsImage::new(3, [1]))
+                .unwrap(),
+            Some(MarsImage::new(1, [1]))
+        );
+        db.drop_table(&123.into()).unwrap();
+        assert_eq!(db.query_all_from_table(&123.into()).unwrap(), vec![]);
+        assert_eq!(
+            db.query_all_from_table(&456.into()).unwrap(),
+            vec![MarsImage::new(2, [1])]
+        );
+        assert_eq!(db.list_tables().unwrap(), vec![ChatKey::from(456)]);
+    }
+
+    #[test]
+    fn test_hostile_table_names() {
+        let db = Sqlite::new_memory();
+        db.insert_to_table(&123.into(), MarsImage::new(1, [1]))
+            .unwrap();
+        // tables which are not named by a chat id are never merged into
+        // `images`, so their names are never put into SQL
+        db.inner
+            .lock()
+            .unwrap()
+            .execute_batch(
+                r#"CREATE TABLE "1]; DROP TABLE images; --" (id INTEGER, sha BLOB NOT NULL PRIMARY KEY);
+                CREATE TABLE "../456" (id INTEGER, sha BLOB NOT NULL PRIMARY KEY);
+                INSERT INTO "../456" VALUES (2, x'02');
+                UPDATE meta SET value = 2 WHERE key = 'schema_version';"#,
+            )
+            .unwrap();
+        assert_eq!(db.legacy_tables().unwrap(), vec![]);
+        assert_eq!(db.migrate().unwrap().len(), 1);
+        assert_eq!(
+            db.query_all_from_table(&123.into()).unwrap(),
+            vec![MarsImage::new(1, [1])]
+        );
+        assert_eq!(db.query_all_from_table(&456.into()).unwrap(), vec![]);
+        assert_eq!(db.list_tables().unwrap(), vec![ChatKey::from(123)]);
+    }
+
+    #[test]
+    fn test_legacy_table_not_canonical() {
+        let db = Sqlite::new_memory();
+        db.inner
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE [0123] (
+                    id INTEGER, sha BLOB NOT NULL PRIMARY KEY, phash INTEGER,
+                    kind INTEGER NOT NULL DEFAULT 0, frames BLOB, duration INTEGER,
+                    time INTEGER
+                );
+                INSERT INTO [0123] (id, sha) VALUES (1, x'01');
+                UPDATE meta SET value = 2 WHERE key = 'schema_version';",
+            )
+            .unwrap();
+        assert_eq!(
+            db.legacy_tables().unwrap(),
+            vec![("0123".to_owned(), ChatKey::from(123))]
+        );
+        assert_eq!(db.migrate().unwrap().len(), 1);
+        assert_eq!(
+            db.query_all_from_table(&123.into()).unwrap(),
+            vec![MarsImage::new(1, [1])]
+        );
+        assert_eq!(db.legacy_tables().unwrap(), vec![]);
     }
 }
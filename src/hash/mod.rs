@@ -0,0 +1,216 @@
+//! Image fingerprints: an exact `Sha3_256` of the file bytes, and an optional
+//! 64-bit perceptual hash that survives re-compression and resizing.
+
+pub mod text;
+pub mod video;
+
+use anyhow::Result;
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+/// The perceptual hash algorithm used to detect near-duplicate images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PerceptualHash {
+    /// Only detect byte-identical images.
+    None,
+    /// Average hash: compare each pixel of an 8x8 thumbnail with the mean.
+    AHash,
+    /// Difference hash: compare horizontally adjacent pixels of a 9x8
+    /// thumbnail.
+    #[default]
+    DHash,
+    /// DCT hash: compare the low frequencies of a 32x32 thumbnail with their
+    /// median. Slowest, but the most robust one.
+    PHash,
+}
+
+/// The fingerprints of one image file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageHash {
+    /// the sha3-256 of the file bytes
+    pub sha: Vec<u8>,
+    /// the perceptual hash, `None` if disabled or the file cannot be decoded
+    pub phash: Option<u64>,
+}
+
+impl ImageHash {
+    /// Hash the file bytes. Decoding errors are not fatal: the image is still
+    /// recorded with its sha.
+    pub fn new(bytes: &[u8], algorithm: PerceptualHash) -> Self {
+        let phash = perceptual_hash(bytes, algorithm)
+            .inspect_err(|e| log::warn!("cannot calculate perceptual hash: {e:?}"))
+            .ok()
+            .flatten();
+        Self {
+            sha: sha3_256(bytes),
+            phash,
+        }
+    }
+}
+
+pub fn sha3_256(bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(bytes);
+    hasher.finalize().as_slice().to_vec()
+}
+
+/// Decode an image and calculate its perceptual hash. Returns `Ok(None)` if
+/// the algorithm is [`PerceptualHash::None`].
+pub fn perceptual_hash(bytes: &[u8], algorithm: PerceptualHash) -> Result<Option<u64>> {
+    if algorithm == PerceptualHash::None {
+        return Ok(None);
+    }
+    let image = image::load_from_memory(bytes)?;
+    Ok(Some(perceptual_hash_image(&image, algorithm)))
+}
+
+pub fn perceptual_hash_image(image: &DynamicImage, algorithm: PerceptualHash) -> u64 {
+    match algorithm {
+        PerceptualHash::None => 0,
+        PerceptualHash::AHash => {
+            let pixels = grayscale(image, 8, 8);
+            let mean = pixels.iter().map(|&x| u32::from(x)).sum::<u32>() / 64;
+            bits(pixels.iter().map(|&x| u32::from(x) > mean))
+        }
+        PerceptualHash::DHash => {
+            let pixels = grayscale(image, 9, 8);
+            bits(
+                pixels
+                    .as_chunks::<9>()
+                    .0
+                    .iter()
+                    .flat_map(|row| row.windows(2).map(|x| x[0] > x[1])),
+            )
+        }
+        PerceptualHash::PHash => {
+            let pixels = grayscale(image, 32, 32)
+                .into_iter()
+                .map(f64::from)
+                .collect::<Vec<_>>();
+            let dct = dct_2d(&pixels, 32, 8);
+            // the DC coefficient only carries the average brightness
+            let mut sorted = dct[1..].to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let median = sorted[sorted.len() / 2];
+            bits(dct.iter().map(|&x| x > median))
+        }
+    }
+}
+
+/// The number of different bits between two perceptual hashes.
+pub const fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn grayscale(image: &DynamicImage, width: u32, height: u32) -> Vec<u8> {
+    image
+        .resize_exact(width, height, FilterType::Triangle)
+        .into_luma8()
+        .into_raw()
+}
+
+/// Pack up to 64 booleans into an u64, the first one is the highest bit.
+fn bits(iter: impl IntoIterator<Item = bool>) -> u64 {
+    iter.into_iter()
+        .take(64)
+        .fold(0, |acc, bit| (acc << 1) | u64::from(bit))
+}
+
+/// The top-left `keep * keep` coefficients of the DCT-II of a `size * size`
+/// matrix, in row-major order.
+#[allow(clippy::cast_precision_loss)]
+fn dct_2d(pixels: &[f64], size: usize, keep: usize) -> Vec<f64> {
+    let n = size as f64;
+    let cos = (0..keep)
+        .map(|u| {
+            (0..size)
+                .map(|x| ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2.0 * n)).cos())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut result = Vec::with_capacity(keep * keep);
+    for v in 0..keep {
+        for u in 0..keep {
+            let sum = (0..size)
+                .flat_map(|y| (0..size).map(move |x| (x, y)))
+                .map(|(x, y)| pixels[y * size + x] * cos[u][x] * cos[v][y])
+                .sum::<f64>();
+            result.push(sum);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{imageops::FilterType, ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    /// A grid of blocks with pseudo random colors.
+    fn sample_image() -> DynamicImage {
+        #[allow(clippy::cast_possible_truncation)]
+        DynamicImage::ImageRgb8(RgbImage::from_fn(256, 192, |x, y| {
+            let block = (x / 32) * 7 + (y / 32) * 13;
+            let seed = block.wrapping_mul(2_654_435_761) >> 8;
+            Rgb([seed as u8, (seed >> 8) as u8, (seed >> 16) as u8])
+        }))
+    }
+
+    fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut buf = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut buf, format).unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(u64::MAX, 0), 64);
+    }
+
+    #[test]
+    fn test_resized_and_recompressed_image_is_similar() {
+        let origin = sample_image();
+        let resized = origin.resize_exact(128, 96, FilterType::Lanczos3);
+        for algorithm in [
+            PerceptualHash::AHash,
+            PerceptualHash::DHash,
+            PerceptualHash::PHash,
+        ] {
+            let a = ImageHash::new(&encode(&origin, ImageFormat::Png), algorithm);
+            let b = ImageHash::new(&encode(&resized, ImageFormat::Jpeg), algorithm);
+            assert_ne!(a.sha, b.sha);
+            let distance = hamming_distance(a.phash.unwrap(), b.phash.unwrap());
+            assert!(distance <= 6, "{algorithm:?}: distance {distance}");
+        }
+    }
+
+    #[test]
+    fn test_different_image_is_not_similar() {
+        let a = sample_image();
+        let b = a.fliph().rotate90();
+        for algorithm in [
+            PerceptualHash::AHash,
+            PerceptualHash::DHash,
+            PerceptualHash::PHash,
+        ] {
+            let distance = hamming_distance(
+                perceptual_hash_image(&a, algorithm),
+                perceptual_hash_image(&b, algorithm),
+            );
+            assert!(distance > 10, "{algorithm:?}: distance {distance}");
+        }
+    }
+
+    #[test]
+    fn test_undecodable_file_still_has_sha() {
+        let hash = ImageHash::new(b"not an image", PerceptualHash::DHash);
+        assert_eq!(hash.sha.len(), 32);
+        assert!(hash.phash.is_none());
+        assert!(ImageHash::new(b"", PerceptualHash::None).phash.is_none());
+    }
+}
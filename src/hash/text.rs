@@ -0,0 +1,151 @@
+//! Keys of text messages and links. The same link is often posted with
+//! different tracking parameters or from a different mirror host, so links
+//! are canonicalised before hashing.
+
+use url::Url;
+
+/// Query parameters which only track the sharer, on any host.
+const TRACKING_PARAMS: [&str; 12] = [
+    "fbclid",
+    "gclid",
+    "dclid",
+    "msclkid",
+    "igshid",
+    "igsh",
+    "si",
+    "feature",
+    "spm",
+    "ref_src",
+    "ref_url",
+    "share_source",
+];
+
+/// Hosts which serve the same posts as `x.com`.
+const X_HOSTS: [&str; 6] = [
+    "twitter.com",
+    "vxtwitter.com",
+    "fxtwitter.com",
+    "fixupx.com",
+    "fixvx.com",
+    "nitter.net",
+];
+
+/// Canonicalise a link, so that the same page gets the same key:
+///
+/// - http is treated as https, and a missing scheme is added
+/// - `www.`, `m.` and `mobile.` are stripped from the host
+/// - `twitter.com` and its mirrors become `x.com`, `youtu.be/{id}` and
+///   `youtube.com/shorts/{id}` become `youtube.com/watch?v={id}`
+/// - tracking parameters such as `utm_*`, `fbclid` and `si` are removed, and
+///   the other parameters are sorted
+/// - the fragment and the trailing slash are dropped
+///
+/// Returns `None` if `link` is not a valid http(s) url.
+pub fn canonicalize_url(link: &str) -> Option<String> {
+    let mut url = Url::parse(link)
+        .or_else(|_| Url::parse(&format!("https://{link}")))
+        .ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    let mut host = url.host_str()?.to_owned();
+    for prefix in ["www.", "m.", "mobile."] {
+        if let Some(x) = host.strip_prefix(prefix) {
+            host = x.to_owned();
+        }
+    }
+    let mut path = url.path().trim_end_matches('/').to_owned();
+    let mut params = url
+        .query_pairs()
+        .filter(|(key, _)| !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    if X_HOSTS.contains(&host.as_str()) {
+        "x.com".clone_into(&mut host);
+    }
+    if host == "x.com" {
+        // `s` and `t` of a shared post only identify the sharer
+        params.clear();
+    }
+    let video_id = match host.as_str() {
+        "youtu.be" => Some(path.trim_start_matches('/').to_owned()),
+        "youtube.com" => path.strip_prefix("/shorts/").map(ToOwned::to_owned),
+        _ => None,
+    };
+    if let Some(id) = video_id.filter(|x| !x.is_empty()) {
+        "youtube.com".clone_into(&mut host);
+        "/watch".clone_into(&mut path);
+        params.push(("v".to_owned(), id));
+    }
+    params.sort();
+
+    url.set_scheme("https").ok()?;
+    url.set_host(Some(&host)).ok()?;
+    url.set_port(None).ok()?;
+    url.set_path(&path);
+    url.set_fragment(None);
+    url.set_query(None);
+    if !params.is_empty() {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    Some(url.into())
+}
+
+/// Normalise a text message: lowercase, and collapse all whitespace.
+pub fn normalize_text(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize_url() {
+        let expected = Some("https://example.com/news/1?id=2".to_owned());
+        for link in [
+            "https://example.com/news/1?id=2",
+            "http://www.example.com/news/1/?utm_source=tg&id=2&fbclid=abc#comments",
+            "example.com/news/1?id=2&si=xyz",
+            "https://EXAMPLE.com:443/news/1?utm_medium=x&id=2",
+        ] {
+            assert_eq!(canonicalize_url(link), expected, "{link}");
+        }
+        assert_eq!(
+            canonicalize_url("https://example.com/?b=1&a=2"),
+            Some("https://example.com/?a=2&b=1".to_owned())
+        );
+        assert_eq!(canonicalize_url("ftp://example.com"), None);
+        assert_eq!(canonicalize_url("not a link"), None);
+    }
+
+    #[test]
+    fn test_canonicalize_mirror_hosts() {
+        let expected = Some("https://x.com/user/status/1".to_owned());
+        for link in [
+            "https://twitter.com/user/status/1?s=20&t=abc",
+            "https://mobile.twitter.com/user/status/1",
+            "https://fxtwitter.com/user/status/1",
+            "https://x.com/user/status/1",
+        ] {
+            assert_eq!(canonicalize_url(link), expected, "{link}");
+        }
+        let expected = Some("https://youtube.com/watch?v=abc".to_owned());
+        for link in [
+            "https://youtu.be/abc?si=xyz",
+            "https://www.youtube.com/watch?v=abc&feature=share",
+            "https://m.youtube.com/watch?v=abc",
+            "https://youtube.com/shorts/abc",
+        ] {
+            assert_eq!(canonicalize_url(link), expected, "{link}");
+        }
+    }
+
+    #[test]
+    fn test_normalize_text() {
+        assert_eq!(normalize_text("  Hello\n  World "), "hello world");
+    }
+}
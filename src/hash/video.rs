@@ -0,0 +1,150 @@
+//! Video fingerprints: perceptual hashes of a few frames spread over the whole
+//! video.
+//!
+//! GIF animations are decoded in pure rust. Other videos need the `video`
+//! feature and an `ffmpeg` executable in `PATH`, otherwise only the thumbnail
+//! generated by telegram is hashed.
+
+use std::io::Cursor;
+
+use anyhow::Result;
+use image::{codecs::gif::GifDecoder, AnimationDecoder, DynamicImage};
+
+use super::{hamming_distance, perceptual_hash_image, PerceptualHash};
+
+/// Decode a GIF and hash `count` evenly spaced frames. The number of frames
+/// is not known before decoding, so every frame is hashed and dropped while
+/// decoding, and only the hashes are kept.
+pub fn gif_frames(bytes: &[u8], count: usize, algorithm: PerceptualHash) -> Result<Vec<u64>> {
+    let hashes = GifDecoder::new(Cursor::new(bytes))?
+        .into_frames()
+        .map(|x| {
+            Ok(perceptual_hash_image(
+                &DynamicImage::ImageRgba8(x?.into_buffer()),
+                algorithm,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(evenly_spaced(hashes, count))
+}
+
+/// Extract `count` evenly spaced frames of a video with `ffmpeg`, and hash
+/// them.
+#[cfg(feature = "video")]
+pub fn ffmpeg_frames(
+    bytes: &[u8],
+    duration: u32,
+    count: usize,
+    algorithm: PerceptualHash,
+) -> Result<Vec<u64>> {
+    use std::{io::Write, process::Command};
+
+    use anyhow::bail;
+
+    // mp4 files may have their index at the end, so ffmpeg needs a seekable
+    // file instead of a pipe.
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(bytes)?;
+    let mut frames = Vec::with_capacity(count);
+    for i in 0..count {
+        #[allow(clippy::cast_precision_loss)]
+        let time = f64::from(duration) * (i as f64 + 0.5) / count as f64;
+        let output = Command::new("ffmpeg")
+            .args(["-v", "error", "-ss", &format!("{time:.3}"), "-i"])
+            .arg(file.path())
+            .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "ffmpeg exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        // seeking to the very end of a short video may produce nothing
+        if output.stdout.is_empty() {
+            continue;
+        }
+        let image = image::load_from_memory(&output.stdout)?;
+        frames.push(perceptual_hash_image(&image, algorithm));
+    }
+    Ok(frames)
+}
+
+/// The fraction of frames in `a` which are similar to any frame in `b`. The
+/// order of frames is ignored, so a trimmed video still matches.
+#[allow(clippy::cast_precision_loss)]
+pub fn frame_match_ratio(a: &[u64], b: &[u64], max_distance: u32) -> f32 {
+    if a.is_empty() {
+        return 0.0;
+    }
+    let matched = a
+        .iter()
+        .filter(|&&x| b.iter().any(|&y| hamming_distance(x, y) <= max_distance))
+        .count();
+    matched as f32 / a.len() as f32
+}
+
+/// Take `count` items spread evenly over `items`.
+fn evenly_spaced<T>(items: Vec<T>, count: usize) -> Vec<T> {
+    let len = items.len();
+    if len <= count {
+        return items;
+    }
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| (i * count) % len < count)
+        .map(|(_, x)| x)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::gif::GifEncoder, Delay, Frame, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn test_evenly_spaced() {
+        assert_eq!(evenly_spaced((0..10).collect(), 5), vec![0, 2, 4, 6, 8]);
+        assert_eq!(evenly_spaced((0..10).collect(), 3), vec![0, 4, 7]);
+        assert_eq!(evenly_spaced((0..2).collect(), 5), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_frame_match_ratio() {
+        assert!((frame_match_ratio(&[0, u64::MAX], &[1], 2) - 0.5).abs() < f32::EPSILON);
+        assert!((frame_match_ratio(&[0, 0b11], &[0b1], 1) - 1.0).abs() < f32::EPSILON);
+        assert!(frame_match_ratio(&[], &[0], 64).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_gif_frames() {
+        let mut bytes = vec![];
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for i in 0..8_u8 {
+                let image = RgbaImage::from_fn(64, 64, |x, y| {
+                    if (x / 8 + y / 8 + u32::from(i)) % 2 == 0 {
+                        Rgba([255, 255, 255, 255])
+                    } else {
+                        Rgba([0, 0, 0, 255])
+                    }
+                });
+                let frame = Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1));
+                encoder.encode_frame(frame).unwrap();
+            }
+        }
+        let frames = gif_frames(&bytes, 4, PerceptualHash::DHash).unwrap();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(
+            frame_match_ratio(
+                &frames,
+                &gif_frames(&bytes, 4, PerceptualHash::DHash).unwrap(),
+                0
+            ),
+            1.0
+        );
+    }
+}
@@ -1,23 +1,29 @@
-#![feature(async_closure)]
-#![feature(try_blocks)]
 #![warn(clippy::pedantic, clippy::nursery, clippy::cargo)]
 #![allow(missing_docs)]
 #![allow(clippy::module_name_repetitions)]
 
+mod backfill;
 mod bot;
 mod cli;
 mod config;
 mod db;
+mod hash;
 mod utils;
 
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
 use clap::Parser;
 use cli::{Cli, SubCommand};
-use config::Config;
-use config_file2::StoreConfigFile;
+use config::{Config, CONFIG};
+use config_file2::{LoadConfigFile, StoreConfigFile};
 use die_exit::DieWith;
-use utils::{config_path, DATA_ROOT_PATH};
+use utils::{config_path, OnceLockDefaultInit, DATA_ROOT_PATH};
 
-use crate::db::DB;
+use crate::db::{dump::Format, ChatKey, DB};
 
 fn main() {
     pretty_env_logger::formatted_builder()
@@ -32,19 +38,216 @@ fn main() {
 
 #[tokio::main]
 async fn retry(cli: Cli) {
+    // the database backend is chosen by config, or overridden by cli
+    let path = cli.config.clone().unwrap_or_else(config_path);
+    CONFIG.get_or_init(|| {
+        let mut config = Config::load_or_default(&path)
+            .die_with(|e| format!("Cannot read config from path `{}`: {e:?}", path.display()));
+        if let Some(backend) = cli.backend {
+            config.backend = backend;
+        }
+        config
+            .validate()
+            .die_with(|e| format!("Invalid config `{}`: {e}", path.display()));
+        config
+    });
     if let Some(command) = cli.command {
-        match command {
-            SubCommand::Delete { chat_id } => DB
-                .drop_table(chat_id.as_str())
-                .die_with(|e| format!("drop table {chat_id} failed: {e:?}")),
-            SubCommand::Export => {
-                Config::default()
-                    .store_without_overwrite(config_path())
-                    .die_with(|e| format!("config file export error: {e:?}"));
-                println!("default config file save to `{:?}`.", config_path());
+        run_command(command);
+    } else {
+        migrate();
+        Box::pin(bot::run(cli)).await;
+    }
+}
+
+/// Run a subcommand.
+fn run_command(command: SubCommand) {
+    // commands using records of the configured database
+    let uses_db = matches!(
+        command,
+        SubCommand::Delete { .. }
+            | SubCommand::Compact { .. }
+            | SubCommand::Prune
+            | SubCommand::Export {
+                chat_id: Some(_),
+                ..
+            }
+            | SubCommand::Import { .. }
+            | SubCommand::Backfill { .. }
+            | SubCommand::ChatConfig { .. }
+    );
+    if uses_db {
+        migrate();
+    }
+    match command {
+        SubCommand::Delete { chat_id } => DB
+            .drop_table(&chat_id)
+            .die_with(|e| format!("drop table {chat_id} failed: {e:?}")),
+        SubCommand::Compact { chat_id } => {
+            let tables = chat_id.map_or_else(
+                || {
+                    DB.list_tables()
+                        .die_with(|e| format!("list tables failed: {e:?}"))
+                },
+                |x| vec![x],
+            );
+            for table in tables {
+                let removed = DB
+                    .compact_table(&table)
+                    .die_with(|e| format!("compact table {table} failed: {e:?}"));
+                println!("chat {table}: removed {removed} records");
+            }
+        }
+        SubCommand::Prune => {
+            let pruned = db::retention::prune(&**DB, db::retention::now())
+                .die_with(|e| format!("prune database failed: {e:?}"));
+            if pruned.is_empty() {
+                println!("no expired record");
+            }
+            for (table, purged) in pruned {
+                println!("chat {table}: purged {purged} records");
             }
         }
+        SubCommand::Migrate { dry_run } => migrate_command(dry_run),
+        SubCommand::Convert { from, to } => {
+            db::convert::convert(&from, &to, |table, counts| {
+                println!(
+                    "chat {table}: copied {} records, {} file ids, {} texts, {} occurrences",
+                    counts.records, counts.unique_ids, counts.texts, counts.occurrences
+                );
+            })
+            .die_with(|e| format!("convert database failed: {e:?}"));
+            println!("all chats are copied and verified");
+        }
+        SubCommand::Backfill {
+            chat_id,
+            export_dir,
+        } => {
+            let result = backfill::backfill(&**DB, &chat_id, &export_dir)
+                .die_with(|e| format!("backfill chat {chat_id} failed: {e:?}"));
+            println!(
+                "chat {chat_id}: recorded {} images, {} repeated, {} skipped",
+                result.recorded, result.repeated, result.skipped
+            );
+        }
+        SubCommand::ChatConfig { chat_id, pairs } => chat_config_command(&chat_id, &pairs),
+        SubCommand::Export {
+            chat_id: Some(chat_id),
+            format,
+            output,
+        } => export_chat(&chat_id, format, output.as_deref()),
+        SubCommand::Export { chat_id: None, .. } => {
+            Config::default()
+                .store_without_overwrite(config_path())
+                .die_with(|e| format!("config file export error: {e:?}"));
+            println!("default config file save to `{}`.", config_path().display());
+        }
+        SubCommand::Import {
+            chat_id,
+            file,
+            format,
+        } => import_chat(&chat_id, &file, format),
+    }
+    if uses_db {
+        DB.close()
+            .die_with(|e| format!("close database failed: {e:?}"));
+    }
+}
+
+/// Show the schema version, and apply or show the pending migrations.
+fn migrate_command(dry_run: bool) {
+    if dry_run {
+        let config = CONFIG.get_or_init_default();
+        let (version, pending) = db::inspect_migrations(config.backend, &config.db_dir)
+            .die_with(|e| format!("inspect database failed: {e:?}"));
+        println!("schema version: {version}");
+        if pending.is_empty() {
+            println!("no pending migration");
+        }
+        for migration in pending {
+            println!("pending {migration}");
+        }
     } else {
-        bot::run(cli).await;
+        let version = DB
+            .schema_version()
+            .die_with(|e| format!("get schema version failed: {e:?}"));
+        println!("schema version: {version}");
+        for migration in DB
+            .migrate()
+            .die_with(|e| format!("migrate database failed: {e:?}"))
+        {
+            println!("applied {migration}");
+        }
+    }
+}
+
+/// Set the settings of a chat by `key=value` pairs, and show them.
+fn chat_config_command(chat_id: &ChatKey, pairs: &[String]) {
+    let mut settings = DB
+        .query_settings(chat_id)
+        .die_with(|e| format!("query settings of chat {chat_id} failed: {e:?}"));
+    if !pairs.is_empty() {
+        for pair in pairs {
+            settings.set_pair(pair).die_with(|e| format!("{e}"));
+        }
+        DB.save_settings(chat_id, &settings)
+            .die_with(|e| format!("save settings of chat {chat_id} failed: {e:?}"));
+    }
+    let config = CONFIG.get_or_init_default().for_chat(&settings);
+    println!("{}", settings.describe(&config));
+}
+
+/// Export all records of a chat to `output`, or to stdout, of `format` or
+/// guessed by the extension of `output`.
+fn export_chat(chat_id: &ChatKey, format: Option<Format>, output: Option<&Path>) {
+    let format = format.unwrap_or_else(|| output.map_or_else(Format::default, Format::of_path));
+    let writer: Box<dyn Write> = match output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).die_with(|e| {
+                format!("create `{}` failed: {e:?}", path.display())
+            })))
+        }
+        None => Box::new(io::stdout().lock()),
     };
+    let counts = db::dump::export(&**DB, chat_id, format, writer)
+        .die_with(|e| format!("export chat {chat_id} failed: {e:?}"));
+    // stdout may be the exported records
+    eprintln!(
+        "chat {chat_id}: exported {} records, {} file ids, {} texts, {} occurrences",
+        counts.records, counts.unique_ids, counts.texts, counts.occurrences
+    );
+}
+
+/// Import records to a chat from `file`, of `format` or guessed by its
+/// extension.
+fn import_chat(chat_id: &ChatKey, file: &Path, format: Option<Format>) {
+    let format = format.unwrap_or_else(|| Format::of_path(file));
+    let reader = File::open(file).die_with(|e| format!("open `{}` failed: {e:?}", file.display()));
+    let counts = db::dump::import(&**DB, chat_id, format, BufReader::new(reader))
+        .die_with(|e| format!("import `{}` failed: {e:?}", file.display()));
+    println!(
+        "chat {chat_id}: imported {} records, {} file ids, {} texts, {} occurrences",
+        counts.records, counts.unique_ids, counts.texts, counts.occurrences
+    );
+}
+
+/// Upgrade the database in place before using it.
+fn migrate() {
+    for migration in DB
+        .migrate()
+        .die_with(|e| format!("migrate database failed: {e:?}"))
+    {
+        log::info!("applied database migration {migration}");
+    }
+    let config = CONFIG.get_or_init_default();
+    if config.chat_retention.is_empty() {
+        return;
+    }
+    for chat in config::migrate_chat_retention(&**DB, config)
+        .die_with(|e| format!("move `chat_retention` to the chat settings failed: {e:?}"))
+    {
+        log::info!("moved `chat_retention` of chat {chat} to its settings");
+    }
+    log::warn!(
+        "`chat_retention` is moved to the settings of the chats, please remove it from the config"
+    );
 }
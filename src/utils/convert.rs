@@ -1,3 +1,6 @@
+//! Byte conversions of sled keys and values.
+#![cfg_attr(not(feature = "sled"), allow(dead_code))]
+
 use std::convert::TryInto;
 
 pub trait IntoVecU8 {
@@ -21,6 +24,45 @@ impl FromVecU8 for i32 {
     }
 }
 
+impl IntoVecU8 for u32 {
+    fn into_vec_u8(self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+}
+
+impl FromVecU8 for u32 {
+    fn from_vec_u8(vec: &[u8]) -> Self {
+        let bytes: [u8; 4] = vec.try_into().expect("Expected a Vec<u8> with length 4");
+        Self::from_le_bytes(bytes)
+    }
+}
+
+impl IntoVecU8 for u64 {
+    fn into_vec_u8(self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+}
+
+impl FromVecU8 for u64 {
+    fn from_vec_u8(vec: &[u8]) -> Self {
+        let bytes: [u8; 8] = vec.try_into().expect("Expected a Vec<u8> with length 8");
+        Self::from_le_bytes(bytes)
+    }
+}
+
+impl IntoVecU8 for i64 {
+    fn into_vec_u8(self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+}
+
+impl FromVecU8 for i64 {
+    fn from_vec_u8(vec: &[u8]) -> Self {
+        let bytes: [u8; 8] = vec.try_into().expect("Expected a Vec<u8> with length 8");
+        Self::from_le_bytes(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -4,6 +4,7 @@ pub mod convert;
 use std::sync::OnceLock;
 
 pub use constant::*;
+#[cfg(feature = "sled")]
 pub use convert::*;
 // pub use telegram::*;
 